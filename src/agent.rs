@@ -0,0 +1,162 @@
+//! Parsing of agent-mode replies into the next action to take.
+
+use serde_json::Value;
+
+const COMMAND_MARKER: &str = "COMMAND:";
+const DONE_MARKER: &str = "DONE";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentAction {
+    /// The model considers the task complete.
+    Done,
+    /// Shell command the model wants to run next.
+    Command(String),
+    /// Neither a command nor a completion; the model has to be asked again.
+    None,
+}
+
+/// Read the text protocol: a `COMMAND: <command>` line or a `DONE` line.
+///
+/// Whichever marker comes first wins. `DONE` only counts at the start of a
+/// line, so prose like "once this is DONE" is not mistaken for completion.
+/// When nothing follows `COMMAND:` on its line, the command is taken from
+/// the next fenced code block or the next non-empty line.
+pub fn parse_agent_response(response: &str) -> AgentAction {
+    let lines: Vec<&str> = response.lines().collect();
+
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(start) = line.find(COMMAND_MARKER) {
+            let rest = line[start + COMMAND_MARKER.len()..].trim();
+            let command = if rest.is_empty() {
+                command_after(&lines[index + 1..])
+            } else {
+                unquote_command(rest)
+            };
+            return if command.is_empty() {
+                AgentAction::None
+            } else {
+                AgentAction::Command(command)
+            };
+        }
+
+        if is_done_line(line) {
+            return AgentAction::Done;
+        }
+    }
+
+    AgentAction::None
+}
+
+/// Cut the reply just before a second `COMMAND:` line, so only the first command is proposed.
+///
+/// Any preamble or explanation around the first command is kept.
+pub fn cut_after_first_command(response: &str) -> &str {
+    let mut offset = 0;
+    let mut seen = false;
+    for line in response.split_inclusive('\n') {
+        if line.contains(COMMAND_MARKER) {
+            if seen {
+                return response[..offset].trim_end();
+            }
+            seen = true;
+        }
+        offset += line.len();
+    }
+    response
+}
+
+/// Read the JSON protocol (`{"thought", "command", "done"}`); `None` if the reply isn't JSON.
+pub fn parse_agent_json(response: &str) -> Option<AgentAction> {
+    let reply: Value = serde_json::from_str(response.trim()).ok()?;
+    if !reply.is_object() {
+        return None;
+    }
+
+    if reply.get("done").and_then(|v| v.as_bool()).unwrap_or(false) {
+        return Some(AgentAction::Done);
+    }
+    Some(
+        match reply.get("command").and_then(|v| v.as_str()).map(str::trim) {
+            Some(command) if !unquote_command(command).is_empty() => {
+                AgentAction::Command(unquote_command(command))
+            }
+            _ => AgentAction::None,
+        },
+    )
+}
+
+/// Feedback for a step: `report` on what ran, led by a note when the user edited the
+/// proposed command, so the model doesn't read the output as its own command's.
+pub fn command_feedback(proposed: &str, ran: &str, report: String) -> String {
+    if proposed.trim() == ran.trim() {
+        report
+    } else {
+        format!("The user edited the command to: {}\n\n{}", ran, report)
+    }
+}
+
+/// Drop leading lines that only echo `command` back (`cmd`, `$ cmd`, `+ cmd`).
+///
+/// Anything else is kept, so real output is never lost to a near match.
+pub fn strip_command_echo<'a>(command: &str, output: &'a str) -> &'a str {
+    let command = command.trim();
+    let mut rest = output;
+    while let Some(line) = rest.lines().next() {
+        let line = line.trim();
+        let echoed = ["$ ", "+ ", "> "]
+            .iter()
+            .find_map(|prompt| line.strip_prefix(prompt))
+            .unwrap_or(line);
+        if line.is_empty() || echoed.trim() != command {
+            break;
+        }
+        rest = rest.split_once('\n').map_or("", |(_, tail)| tail);
+    }
+    rest
+}
+
+fn command_after(lines: &[&str]) -> String {
+    let mut rest = lines.iter().map(|l| l.trim()).skip_while(|l| l.is_empty());
+
+    match rest.next() {
+        Some(fence) if fence.starts_with("```") => rest
+            .take_while(|l| !l.starts_with("```"))
+            .collect::<Vec<&str>>()
+            .join("\n"),
+        Some(line) => line.to_string(),
+        None => String::new(),
+    }
+}
+
+// `ls -la` or ```ls -la``` on one line; backticks inside the command are left alone
+fn unquote_command(command: &str) -> String {
+    let ticks = command.len() - command.trim_start_matches('`').len();
+    let closing = command.len() - command.trim_end_matches('`').len();
+    if ticks == 0 || closing == 0 || ticks * 2 > command.len() {
+        return command.trim().to_string();
+    }
+
+    let inner = &command[ticks..command.len() - closing];
+    // `a` && `b` is two quoted words, not one quoted command
+    if ticks < 3 && inner.contains('`') {
+        return command.trim().to_string();
+    }
+    // A one-line fence may carry a language tag: ```sh ls -la```
+    let inner = match (ticks >= 3, inner.split_once(' ')) {
+        (true, Some(("sh" | "bash" | "shell" | "zsh" | "console", rest))) => rest,
+        _ => inner,
+    };
+    inner.trim().to_string()
+}
+
+// "DONE", "**DONE**", "DONE." or "DONE: all files renamed", but not "DONE-ish" prose
+fn is_done_line(line: &str) -> bool {
+    let line = line.trim().trim_start_matches(['*', '`']);
+    match line.strip_prefix(DONE_MARKER) {
+        Some(rest) => rest
+            .chars()
+            .next()
+            .is_none_or(|c| matches!(c, '*' | '`' | '.' | '!' | ':')),
+        None => false,
+    }
+}
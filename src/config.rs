@@ -0,0 +1,116 @@
+//! User defaults stored in `$XDG_CONFIG_HOME/ask/config.toml`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+const CONFIG_PATH: &str = "ask/config.toml";
+
+// All keys are optional; missing ones fall back to the built-in defaults
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    /// Used when OPENAI_API_KEY is not set.
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub base_url: Option<String>,
+    /// Model used for side-requests such as `--title-auto`.
+    pub title_model: Option<String>,
+    /// Appended to every prompt when it is sent, e.g. "Assume I'm on Arch Linux".
+    pub prompt_suffix: Option<String>,
+    /// File name after `gpt_transcript-`, with `{pid}`, `{name}`, `{date}` and `{model}` placeholders.
+    pub transcript_template: Option<String>,
+    /// Model name or prefix -> base URL, for models served somewhere else.
+    #[serde(default)]
+    pub routes: HashMap<String, String>,
+    /// Named prompt presets selected with `--persona`.
+    #[serde(default)]
+    pub personas: HashMap<String, Persona>,
+}
+
+/// A system prompt with optional defaults, e.g. `[personas.reviewer]`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Persona {
+    pub prompt: String,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+}
+
+impl Config {
+    /// Base URL routed for `model`; the longest matching prefix wins.
+    pub fn route_for(&self, model: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, base_url)| base_url.as_str())
+    }
+
+    /// The persona called `name`, or an error listing the configured ones.
+    pub fn persona(&self, name: &str) -> Result<&Persona, String> {
+        self.personas.get(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.personas.keys().map(|k| k.as_str()).collect();
+            names.sort_unstable();
+            if names.is_empty() {
+                format!("Unknown persona '{}': no personas are configured", name)
+            } else {
+                format!(
+                    "Unknown persona '{}'; available: {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+        })
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join(CONFIG_PATH))
+}
+
+pub fn load_config() -> Result<Config, String> {
+    let path = match config_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(Config::default()),
+    };
+
+    let data = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    toml::from_str(&data).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+/// Update a single key in the config file, keeping everything else untouched.
+pub fn set_config_value(key: &str, value: toml::Value) -> Result<PathBuf, String> {
+    let path = config_path().ok_or("Unable to locate a config directory")?;
+
+    let mut table: toml::Table = if path.exists() {
+        let data = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        toml::from_str(&data).map_err(|e| e.to_string())?
+    } else {
+        toml::Table::new()
+    };
+    table.insert(key.to_string(), value);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    // The file may hold an API key, so it is never readable by others, not even briefly
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| e.to_string())?;
+    // An existing file keeps its mode on open, so tighten it before writing
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+    file.write_all(table.to_string().as_bytes())
+        .map_err(|e| e.to_string())?;
+    Ok(path)
+}
@@ -0,0 +1,1170 @@
+//! Core of `ask`: conversation types and a small blocking client for
+//! OpenAI-compatible chat and completions endpoints.
+//!
+//! ```no_run
+//! use ask_rs::{Client, ClientConfig, Message};
+//!
+//! let client = Client::new(ClientConfig::new("sk-...", "gpt-4o"));
+//! let reply = client.send(&[Message::new("user", "Hi there")]).unwrap();
+//! println!("{}", reply.text());
+//! ```
+
+pub mod agent;
+pub mod config;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_MODEL: &str = "o1-mini";
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com";
+pub const DEFAULT_MAX_TOKENS: u32 = 2048;
+pub const DEFAULT_TEMPERATURE: f64 = 0.6;
+pub const SEED_PROMPT: &str = "You are ChatConcise, a very advanced LLM designed for experienced users. As ChatConcise you oblige to adhere to the following directives UNLESS overridden by the user:\nBe concise, proactive, helpful and efficient. Do not say anything more than what needed, but also, DON'T BE LAZY. Provide ONLY code when an implementation is needed. DO NOT USE MARKDOWN.";
+/// Terse seed for reasoning models, which do better without a long persona.
+pub const MINIMAL_SEED_PROMPT: &str = "Be concise and do not use markdown.";
+
+const CHAT_ENDPOINT: &str = "/v1/chat/completions";
+const COMPLETIONS_ENDPOINT: &str = "/v1/completions";
+// Canonical roles; --role-map renames them only on the way out
+const ALLOWED_ROLES: &[&str] = &["system", "developer", "user", "assistant", "tool"];
+const RESPONSES_ENDPOINT: &str = "/v1/responses";
+const MODELS_ENDPOINT: &str = "/v1/models";
+const FILES_ENDPOINT: &str = "/v1/files";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Message {
+    pub role: String,
+    pub content: Value,
+    /// Tool calls requested by the assistant, kept so a tool-result turn can follow.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Value>,
+    /// Pinned messages are never dropped by context management.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub pinned: bool,
+    /// Model that produced an assistant reply; absent on older transcripts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Token usage the provider reported for this reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Server-side id of a reply from the Responses API, used to chain the next turn.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_id: Option<String>,
+    /// Unix time in seconds when the message was added; absent on older transcripts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+// USD per million prompt / completion tokens, by model prefix
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("gpt-4", 30.00, 60.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4.1", 2.00, 8.00),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1-nano", 0.10, 0.40),
+    ("o1", 15.00, 60.00),
+    ("o1-mini", 1.10, 4.40),
+    ("o3", 2.00, 8.00),
+    ("o3-mini", 1.10, 4.40),
+    ("o4-mini", 1.10, 4.40),
+];
+
+impl Usage {
+    /// Estimated cost in USD, or `None` for models without a known price.
+    pub fn cost(&self, model: &str) -> Option<f64> {
+        PRICES
+            .iter()
+            .filter(|(prefix, _, _)| model.starts_with(prefix))
+            .max_by_key(|(prefix, _, _)| prefix.len())
+            .map(|&(_, prompt, completion)| {
+                (self.prompt_tokens as f64 * prompt + self.completion_tokens as f64 * completion)
+                    / 1_000_000.0
+            })
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Message {
+    pub fn new(role: &str, content: &str) -> Self {
+        Message {
+            role: role.to_string(),
+            content: Value::String(content.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Plain text of the message; for multimodal content, the first text part.
+    pub fn text(&self) -> &str {
+        if let Some(text) = self.content.as_str() {
+            text
+        } else if let Some(array) = self.content.as_array() {
+            array
+                .first()
+                .and_then(|item| item.get("text"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+        } else {
+            ""
+        }
+    }
+}
+
+/// On-disk transcript format; bump it together with a step in `ConversationState::migrate`.
+pub const TRANSCRIPT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConversationState {
+    /// Format version; transcripts written before versioning read as 0.
+    #[serde(default)]
+    pub version: u32,
+    pub model: String,
+    pub messages: Vec<Message>,
+    /// Ids of files uploaded to the provider during this conversation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    /// Short name shown when listing conversations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Sampling settings the conversation was started with; absent on older transcripts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Config persona the conversation was seeded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+}
+
+impl ConversationState {
+    /// A fresh conversation seeded with the ChatConcise persona.
+    pub fn new(model: &str) -> Self {
+        Self::with_seed(model, SEED_PROMPT)
+    }
+
+    pub fn with_seed(model: &str, seed: &str) -> Self {
+        ConversationState {
+            version: TRANSCRIPT_VERSION,
+            model: model.to_string(),
+            messages: vec![Message::new(seed_role(model), seed)],
+            ..Default::default()
+        }
+    }
+
+    /// The most recent assistant reply; `None` while only the seed and prompts exist.
+    pub fn last_assistant_message(&self) -> Option<&Message> {
+        self.messages.iter().rev().find(|m| m.role == "assistant")
+    }
+
+    /// Load a transcript, picking JSON or YAML from the file extension.
+    ///
+    /// Transcripts from older versions are migrated in memory only, so reading one never
+    /// touches the file; the upgrade lands on disk with the next `save`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = fs::read_to_string(path).map_err(Error::Io)?;
+        let mut state: Self = match TranscriptFormat::from_path(path) {
+            TranscriptFormat::Json => serde_json::from_str(&data).map_err(Error::Json)?,
+            TranscriptFormat::Yaml => serde_yaml::from_str(&data).map_err(Error::Yaml)?,
+        };
+        if state.version < TRANSCRIPT_VERSION {
+            state.migrate();
+        }
+        Ok(state)
+    }
+
+    // One step per version, applied in order
+    fn migrate(&mut self) {
+        if self.version == 0 {
+            // v0 predates versioning; every field added since then has a serde default
+            self.version = 1;
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let data = match TranscriptFormat::from_path(path) {
+            TranscriptFormat::Json => serde_json::to_string(self).map_err(Error::Json)?,
+            TranscriptFormat::Yaml => serde_yaml::to_string(self).map_err(Error::Yaml)?,
+        };
+        fs::write(path, data).map_err(Error::Io)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    Json,
+    Yaml,
+}
+
+impl TranscriptFormat {
+    /// Transcripts are JSON unless the file name ends in `.yaml`/`.yml`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => TranscriptFormat::Yaml,
+            _ => TranscriptFormat::Json,
+        }
+    }
+
+    /// Suffix appended to transcript file names; JSON keeps the historical bare name.
+    pub fn extension(self) -> &'static str {
+        match self {
+            TranscriptFormat::Json => "",
+            TranscriptFormat::Yaml => ".yaml",
+        }
+    }
+}
+
+/// o-series models (o1, o3, o4-mini, ...) reject sampling parameters and the system role.
+pub fn is_reasoning_model(model: &str) -> bool {
+    let mut chars = model.chars();
+    model.contains("o1-")
+        || (chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit()))
+}
+
+// Open-weight reasoning models that put their reasoning inline in <think> tags
+const THINKING_TAG_MODELS: &[&str] = &["deepseek-r1", "qwq", "r1-distill"];
+
+/// Models whose replies are likely to include `<think>` blocks.
+pub fn emits_thinking_tags(model: &str) -> bool {
+    is_reasoning_model(model) || THINKING_TAG_MODELS.iter().any(|m| model.contains(m))
+}
+
+/// Remove `<think>...</think>` (and `<thinking>`) blocks, keeping the answer around them.
+pub fn strip_thinking_tags(text: &str) -> String {
+    let tags = regex::Regex::new(r"(?s)<(think|thinking)>.*?</(think|thinking)>\s*").unwrap();
+    tags.replace_all(text, "").trim_start().to_string()
+}
+
+/// Models that accept a `prediction` (predicted outputs) parameter.
+pub fn supports_prediction(model: &str) -> bool {
+    model.starts_with("gpt-4o") || model.starts_with("gpt-4.1")
+}
+
+// Known context windows by model prefix, in tokens
+const CONTEXT_WINDOWS: &[(&str, u32)] = &[
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+];
+
+/// Context window for `model`, or `None` when it isn't a known model.
+pub fn context_window(model: &str) -> Option<u32> {
+    CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|&(_, tokens)| tokens)
+}
+
+/// Common model ids by provider, for picking a `--model` without a network round trip.
+pub const KNOWN_MODELS: &[(&str, &str)] = &[
+    ("openai", "gpt-4o"),
+    ("openai", "gpt-4o-mini"),
+    ("openai", "gpt-4.1"),
+    ("openai", "gpt-4.1-mini"),
+    ("openai", "gpt-4.1-nano"),
+    ("openai", "gpt-4-turbo"),
+    ("openai", "gpt-3.5-turbo"),
+    ("openai", "o1"),
+    ("openai", "o1-mini"),
+    ("openai", "o3"),
+    ("openai", "o3-mini"),
+    ("openai", "o4-mini"),
+    ("anthropic", "claude-3-5-sonnet-latest"),
+    ("anthropic", "claude-3-5-haiku-latest"),
+    ("anthropic", "claude-3-opus-latest"),
+    ("ollama", "llama3.1"),
+    ("ollama", "mistral"),
+    ("ollama", "qwen2.5-coder"),
+    ("ollama", "deepseek-r1"),
+];
+
+/// Rough token count (about four characters per token plus per-message overhead).
+pub fn estimate_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            let chars = match &m.content {
+                Value::String(text) => text.chars().count(),
+                Value::Array(parts) => parts
+                    .iter()
+                    .filter_map(|p| p.get("text").and_then(|v| v.as_str()))
+                    .map(|text| text.chars().count())
+                    .sum(),
+                _ => 0,
+            };
+            chars / 4 + 4
+        })
+        .sum()
+}
+
+/// Models that accept `response_format: json_object`.
+pub fn supports_json_mode(model: &str) -> bool {
+    [
+        "gpt-4o",
+        "gpt-4.1",
+        "gpt-4-turbo",
+        "gpt-3.5-turbo",
+        "o3",
+        "o4-",
+    ]
+    .iter()
+    .any(|prefix| model.starts_with(prefix))
+}
+
+/// Models that accept `response_format: json_schema` (structured outputs).
+pub fn supports_json_schema(model: &str) -> bool {
+    model == "o1"
+        || ["gpt-4o", "gpt-4.1", "o1-2", "o3", "o4-"]
+            .iter()
+            .any(|prefix| model.starts_with(prefix))
+}
+
+/// Reasoning models reject `stop` along with the other sampling parameters.
+pub fn supports_stop(model: &str) -> bool {
+    !is_reasoning_model(model)
+}
+
+pub fn seed_role(model: &str) -> &'static str {
+    if is_reasoning_model(model) {
+        "user"
+    } else {
+        "system"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub api_key: String,
+    pub base_url: String,
+    pub model: String,
+    pub temperature: f64,
+    pub max_tokens: u32,
+    /// Sent as the `user` field so the provider can attribute usage.
+    pub user: Option<String>,
+    /// Send a single concatenated prompt to the completions endpoint.
+    pub flatten: bool,
+    /// Canonical role -> role name expected by the backend.
+    pub role_map: HashMap<String, String>,
+    /// Save every raw response body into this directory before parsing it.
+    pub debug_dump_dir: Option<PathBuf>,
+    /// Expected output, sent as a predicted-outputs hint.
+    pub prediction: Option<String>,
+    /// Stop sequences; dropped for models that don't support them.
+    pub stop: Vec<String>,
+    /// Ask for a JSON object reply; ignored for models without JSON mode.
+    pub json_mode: bool,
+    /// Merge consecutive same-role turns for backends that require alternating roles.
+    pub alternate_roles: bool,
+    /// Fail every request with `Error::Offline` instead of touching the network.
+    pub offline: bool,
+    /// JSON Schema the reply must follow; takes precedence over `json_mode`.
+    pub json_schema: Option<Value>,
+    /// Refuse to send messages whose role the API would reject.
+    pub strict_roles: bool,
+    /// Use the Responses API and send only the turns after the last stored reply.
+    pub responses_api: bool,
+    /// Other keys to switch to, in order, when the provider answers 429.
+    pub fallback_keys: Vec<String>,
+    /// Write each assembled request (key redacted) here before sending it.
+    pub save_request: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    pub fn new(api_key: &str, model: &str) -> Self {
+        ClientConfig {
+            api_key: api_key.to_string(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            model: model.to_string(),
+            temperature: DEFAULT_TEMPERATURE,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            user: None,
+            flatten: false,
+            role_map: HashMap::new(),
+            debug_dump_dir: None,
+            prediction: None,
+            stop: Vec::new(),
+            json_mode: false,
+            alternate_roles: false,
+            offline: false,
+            json_schema: None,
+            strict_roles: true,
+            responses_api: false,
+            fallback_keys: Vec::new(),
+            save_request: None,
+        }
+    }
+
+    fn outgoing_role<'a>(&'a self, role: &'a str) -> &'a str {
+        self.role_map.get(role).map(|r| r.as_str()).unwrap_or(role)
+    }
+
+    fn incoming_role(&self, role: &str) -> String {
+        self.role_map
+            .iter()
+            .find(|(_, mapped)| mapped.as_str() == role)
+            .map(|(canonical, _)| canonical.clone())
+            .unwrap_or_else(|| role.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    Status(reqwest::StatusCode),
+    /// The provider answered, but not with anything we could read a reply from.
+    UnexpectedResponse(Value),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    /// The client was configured with `offline` and refused to make a request.
+    Offline,
+    /// A message's role isn't one the chat API accepts: (index, role).
+    InvalidRole(usize, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // reqwest errors can embed the URL but never the auth header
+            Error::Http(e) => write!(f, "HTTP request error: {}", e),
+            Error::Status(status) => write!(f, "HTTP {}", status),
+            Error::UnexpectedResponse(data) => write!(
+                f,
+                "Error processing API return. Full response ahead:\n{}\n",
+                data
+            ),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Yaml(e) => write!(f, "{}", e),
+            Error::Offline => write!(f, "Network access is disabled, no request was sent"),
+            Error::InvalidRole(index, role) => write!(
+                f,
+                "Message {} has role '{}'; expected one of {}",
+                index,
+                role,
+                ALLOWED_ROLES.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Yaml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// An assistant message plus response metadata.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub message: Message,
+    /// Provider request id, useful when filing support tickets.
+    pub request_id: Option<String>,
+    pub rate_limit: RateLimit,
+}
+
+/// Quota reported through the `x-ratelimit-*` headers, when the provider sends them.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    pub limit_requests: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+}
+
+impl RateLimit {
+    /// True when either quota has dropped below `fraction` of its limit.
+    pub fn is_low(&self, fraction: f64) -> bool {
+        let low = |remaining: Option<u64>, limit: Option<u64>| match (remaining, limit) {
+            (Some(remaining), Some(limit)) if limit > 0 => {
+                (remaining as f64) < (limit as f64) * fraction
+            }
+            _ => false,
+        };
+        low(self.remaining_requests, self.limit_requests)
+            || low(self.remaining_tokens, self.limit_tokens)
+    }
+}
+
+pub struct Client {
+    config: ClientConfig,
+    http: reqwest::blocking::Client,
+    // Index into `api_key` followed by `fallback_keys`
+    active_key: AtomicUsize,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Self {
+        Client {
+            config,
+            http: reqwest::blocking::Client::new(),
+            active_key: AtomicUsize::new(0),
+        }
+    }
+
+    /// The key requests are currently sent with: 0 is `api_key`, then `fallback_keys` in order.
+    pub fn active_key(&self) -> usize {
+        self.active_key.load(Ordering::Relaxed)
+    }
+
+    fn api_key(&self) -> &str {
+        match self.active_key() {
+            0 => &self.config.api_key,
+            n => &self.config.fallback_keys[n - 1],
+        }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Send the conversation and return the assistant's reply.
+    pub fn send(&self, messages: &[Message]) -> Result<Message, Error> {
+        self.send_detailed(messages).map(|reply| reply.message)
+    }
+
+    /// Like [`Client::send`], but also returns what the provider reported about the call.
+    pub fn send_detailed(&self, messages: &[Message]) -> Result<Reply, Error> {
+        if self.config.strict_roles {
+            check_roles(messages)?;
+        }
+        let (endpoint, body) = self.build_body(messages);
+        // Saved even when offline, so a request can be captured without sending it
+        if let Some(path) = &self.config.save_request {
+            self.write_request(path, endpoint, &body)?;
+        }
+        self.ensure_online()?;
+
+        // A rate-limited key hands over to the next one; each key is tried once per call
+        let keys = 1 + self.config.fallback_keys.len();
+        let mut attempts = 1;
+        let response = loop {
+            let response = self
+                .http
+                .post(format!("{}{}", self.config.base_url, endpoint))
+                .header("Authorization", format!("Bearer {}", self.api_key()))
+                .json(&body)
+                .send()
+                .map_err(Error::Http)?;
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || attempts >= keys {
+                break response;
+            }
+            self.active_key
+                .store((self.active_key() + 1) % keys, Ordering::Relaxed);
+            attempts += 1;
+        };
+
+        let headers = response.headers();
+        let request_id = ["x-request-id", "openai-request-id"]
+            .iter()
+            .find_map(|name| headers.get(*name))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        };
+        let rate_limit = RateLimit {
+            limit_requests: header("x-ratelimit-limit-requests"),
+            remaining_requests: header("x-ratelimit-remaining-requests"),
+            limit_tokens: header("x-ratelimit-limit-tokens"),
+            remaining_tokens: header("x-ratelimit-remaining-tokens"),
+        };
+
+        let data = self.read_body(response)?;
+        Ok(Reply {
+            message: self.parse_response(data)?,
+            request_id,
+            rate_limit,
+        })
+    }
+
+    /// Send a request saved by `save_request` verbatim and return the raw response.
+    ///
+    /// Only the endpoint path is reused: the request goes to this client's
+    /// base URL, so a shared file can't redirect the key to another host.
+    pub fn replay_request(&self, saved: &Value) -> Result<Value, Error> {
+        self.ensure_online()?;
+        let (Some(endpoint), Some(body)) = (saved["endpoint"].as_str(), saved.get("body")) else {
+            return Err(Error::UnexpectedResponse(saved.clone()));
+        };
+
+        let response = self
+            .http
+            .post(format!("{}{}", self.config.base_url, endpoint))
+            .header("Authorization", format!("Bearer {}", self.api_key()))
+            .json(body)
+            .send()
+            .map_err(Error::Http)?;
+        self.read_body(response)
+    }
+
+    fn write_request(&self, path: &Path, endpoint: &str, body: &Value) -> Result<(), Error> {
+        let request = serde_json::json!({
+            "url": format!("{}{}", self.config.base_url, endpoint),
+            "endpoint": endpoint,
+            "headers": {
+                "Authorization": format!("Bearer {}", redact_key(self.api_key())),
+                "Content-Type": "application/json",
+            },
+            "body": body,
+        });
+        let data = serde_json::to_string_pretty(&request).map_err(Error::Json)?;
+        fs::write(path, data).map_err(Error::Io)
+    }
+
+    /// Ids of the models available to this key.
+    pub fn list_models(&self) -> Result<Vec<String>, Error> {
+        self.ensure_online()?;
+        let response = self
+            .http
+            .get(format!("{}{}", self.config.base_url, MODELS_ENDPOINT))
+            .header("Authorization", format!("Bearer {}", self.api_key()))
+            .send()
+            .map_err(Error::Http)?;
+
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status()));
+        }
+
+        let data = self.read_body(response)?;
+        Ok(data
+            .get("data")
+            .and_then(|v| v.as_array())
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|m| m.get("id").and_then(|v| v.as_str()))
+                    .map(|id| id.to_string())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Upload a file through the provider's Files API and return its id.
+    pub fn upload_file(&self, path: &Path) -> Result<String, Error> {
+        self.ensure_online()?;
+        let form = reqwest::blocking::multipart::Form::new()
+            .text("purpose", "user_data")
+            .file("file", path)
+            .map_err(Error::Io)?;
+
+        let response = self
+            .http
+            .post(format!("{}{}", self.config.base_url, FILES_ENDPOINT))
+            .header("Authorization", format!("Bearer {}", self.api_key()))
+            .multipart(form)
+            .send()
+            .map_err(Error::Http)?;
+
+        let data = self.read_body(response)?;
+        match data.get("id").and_then(|v| v.as_str()) {
+            Some(id) => Ok(id.to_string()),
+            None => Err(Error::UnexpectedResponse(data)),
+        }
+    }
+
+    fn read_body(&self, response: reqwest::blocking::Response) -> Result<Value, Error> {
+        let body = response.text().map_err(Error::Http)?;
+
+        if let Some(dir) = &self.config.debug_dump_dir {
+            let stamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default();
+            let path = dir.join(format!("response-{}.json", stamp));
+            // A debugging aid must never break the request itself
+            if let Err(e) = fs::create_dir_all(dir).and_then(|_| fs::write(&path, &body)) {
+                eprintln!("Unable to dump response to {}: {}", path.display(), e);
+            }
+        }
+
+        serde_json::from_str(&body).map_err(Error::Json)
+    }
+
+    fn ensure_online(&self) -> Result<(), Error> {
+        if self.config.offline {
+            Err(Error::Offline)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn build_body(&self, messages: &[Message]) -> (&'static str, Value) {
+        // The transcript keeps canonical roles; only the outgoing copy is renamed
+        let messages: Vec<Message> = messages
+            .iter()
+            .map(|m| Message {
+                role: self.config.outgoing_role(&m.role).to_string(),
+                ..m.clone()
+            })
+            .collect();
+        let messages = if self.config.alternate_roles {
+            merge_consecutive_roles(&messages)
+        } else {
+            messages
+        };
+        // Local bookkeeping such as `pinned` and `model` never goes over the wire
+        let wire_messages: Vec<Value> = messages
+            .iter()
+            .map(|m| {
+                let mut message = serde_json::json!({ "role": m.role, "content": m.content });
+                if let Some(tool_calls) = &m.tool_calls {
+                    message["tool_calls"] = tool_calls.clone();
+                }
+                message
+            })
+            .collect();
+
+        if self.config.responses_api && !self.config.flatten {
+            return self.build_responses_body(&messages);
+        }
+
+        let (endpoint, mut body) = if self.config.flatten {
+            (
+                COMPLETIONS_ENDPOINT,
+                serde_json::json!({
+                    "prompt": flatten_messages(&messages),
+                    "model": self.config.model,
+                }),
+            )
+        } else {
+            (
+                CHAT_ENDPOINT,
+                serde_json::json!({
+                    "messages": wire_messages,
+                    "model": self.config.model,
+                }),
+            )
+        };
+
+        if let Some(user) = &self.config.user {
+            body["user"] = serde_json::json!(user);
+        }
+
+        if let Some(prediction) = &self.config.prediction {
+            body["prediction"] = serde_json::json!({ "type": "content", "content": prediction });
+        }
+
+        if !self.config.stop.is_empty() && supports_stop(&self.config.model) {
+            body["stop"] = serde_json::json!(self.config.stop);
+        }
+
+        if let Some(schema) = self
+            .config
+            .json_schema
+            .as_ref()
+            .filter(|_| !self.config.flatten && supports_json_schema(&self.config.model))
+        {
+            body["response_format"] = serde_json::json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema, "strict": true },
+            });
+        } else if self.config.json_mode
+            && !self.config.flatten
+            && supports_json_mode(&self.config.model)
+        {
+            body["response_format"] = serde_json::json!({ "type": "json_object" });
+        }
+
+        if !is_reasoning_model(&self.config.model) {
+            body["max_tokens"] = serde_json::json!(self.config.max_tokens);
+            body["temperature"] = serde_json::json!(self.config.temperature);
+        }
+
+        (endpoint, body)
+    }
+
+    // The server already holds everything up to the last reply it stored
+    fn build_responses_body(&self, messages: &[Message]) -> (&'static str, Value) {
+        let chained = messages.iter().rposition(|m| m.response_id.is_some());
+        let new_turns = &messages[chained.map_or(0, |i| i + 1)..];
+        let input: Vec<Value> = new_turns
+            .iter()
+            .map(
+                |m| serde_json::json!({ "role": m.role, "content": responses_content(&m.content) }),
+            )
+            .collect();
+
+        let mut body = serde_json::json!({
+            "model": self.config.model,
+            "input": input,
+            "store": true,
+        });
+        if let Some(index) = chained {
+            body["previous_response_id"] = serde_json::json!(messages[index].response_id);
+        }
+        if let Some(user) = &self.config.user {
+            body["user"] = serde_json::json!(user);
+        }
+
+        if let Some(schema) = self
+            .config
+            .json_schema
+            .as_ref()
+            .filter(|_| supports_json_schema(&self.config.model))
+        {
+            body["text"] = serde_json::json!({
+                "format": { "type": "json_schema", "name": "response", "schema": schema, "strict": true },
+            });
+        } else if self.config.json_mode && supports_json_mode(&self.config.model) {
+            body["text"] = serde_json::json!({ "format": { "type": "json_object" } });
+        }
+
+        if !is_reasoning_model(&self.config.model) {
+            body["max_output_tokens"] = serde_json::json!(self.config.max_tokens);
+            body["temperature"] = serde_json::json!(self.config.temperature);
+        }
+
+        (RESPONSES_ENDPOINT, body)
+    }
+
+    fn parse_response(&self, data: Value) -> Result<Message, Error> {
+        if let Some(output) = data.get("output").and_then(|o| o.as_array()) {
+            return parse_responses_output(output, &data);
+        }
+
+        let choice = match data.get("choices").and_then(|c| c.get(0)) {
+            Some(choice) => choice,
+            None => return Err(Error::UnexpectedResponse(data)),
+        };
+        let usage = data
+            .get("usage")
+            .and_then(|u| serde_json::from_value::<Usage>(u.clone()).ok());
+
+        // Completions-style responses carry plain text instead of a message
+        if let Some(text) = choice.get("text").and_then(|v| v.as_str()) {
+            return Ok(Message {
+                usage,
+                ..Message::new("assistant", text.trim())
+            });
+        }
+
+        match choice.get("message") {
+            Some(message) => Ok(Message {
+                role: self
+                    .config
+                    .incoming_role(message.get("role").and_then(|v| v.as_str()).unwrap_or("")),
+                content: message.get("content").unwrap_or(&Value::Null).clone(),
+                tool_calls: message.get("tool_calls").filter(|v| !v.is_null()).cloned(),
+                usage,
+                ..Default::default()
+            }),
+            None => Err(Error::UnexpectedResponse(data.clone())),
+        }
+    }
+}
+
+/// Turn plain input into a content array (if needed) and add `part` to it.
+pub fn append_content_part(content: &mut Value, part: Value) {
+    if !content.is_array() {
+        let text = content.as_str().unwrap_or("");
+        *content = serde_json::json!([{ "type": "text", "text": text }]);
+    }
+    if let Some(parts) = content.as_array_mut() {
+        parts.push(part);
+    }
+}
+
+/// Put `prefix` in front of the message text (the first text part for multimodal content).
+pub fn prepend_text(content: &mut Value, prefix: &str) {
+    match content {
+        Value::String(text) => *text = format!("{}{}", prefix, text),
+        Value::Array(parts) => {
+            match parts
+                .iter_mut()
+                .find_map(|p| p.get_mut("text").filter(|t| t.is_string()))
+            {
+                Some(text) => {
+                    *text = Value::String(format!("{}{}", prefix, text.as_str().unwrap()))
+                }
+                None => parts.insert(0, serde_json::json!({ "type": "text", "text": prefix })),
+            }
+        }
+        _ => *content = Value::String(prefix.to_string()),
+    }
+}
+
+/// Put `suffix` after the message text (the last text part for multimodal content).
+pub fn append_text(content: &mut Value, suffix: &str) {
+    match content {
+        Value::String(text) => text.push_str(suffix),
+        Value::Array(parts) => {
+            match parts
+                .iter_mut()
+                .rev()
+                .find_map(|p| p.get_mut("text").filter(|t| t.is_string()))
+            {
+                Some(text) => {
+                    *text = Value::String(format!("{}{}", text.as_str().unwrap(), suffix))
+                }
+                None => parts.push(serde_json::json!({ "type": "text", "text": suffix })),
+            }
+        }
+        _ => *content = Value::String(suffix.to_string()),
+    }
+}
+
+/// Fold runs of same-role messages into one; tool-call turns are never merged.
+pub fn merge_consecutive_roles(messages: &[Message]) -> Vec<Message> {
+    let mut merged: Vec<Message> = Vec::new();
+
+    for message in messages {
+        match merged.last_mut() {
+            Some(previous)
+                if previous.role == message.role
+                    && previous.tool_calls.is_none()
+                    && message.tool_calls.is_none() =>
+            {
+                match (&mut previous.content, &message.content) {
+                    (Value::String(text), Value::String(next)) => {
+                        text.push_str("\n\n");
+                        text.push_str(next);
+                    }
+                    (content, Value::Array(parts)) => {
+                        for part in parts {
+                            append_content_part(content, part.clone());
+                        }
+                    }
+                    (content, next) => append_content_part(
+                        content,
+                        serde_json::json!({ "type": "text", "text": next.as_str().unwrap_or("") }),
+                    ),
+                }
+            }
+            _ => merged.push(message.clone()),
+        }
+    }
+
+    merged
+}
+
+/// Concatenate the conversation into one labeled prompt for endpoints without chat format.
+pub fn flatten_messages(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+
+    for message in messages {
+        let text = if let Some(text) = message.content.as_str() {
+            text.to_string()
+        } else if let Some(array) = message.content.as_array() {
+            array
+                .iter()
+                .filter_map(|item| item.get("text").and_then(|v| v.as_str()))
+                .collect::<Vec<&str>>()
+                .join("\n")
+        } else {
+            continue;
+        };
+        prompt.push_str(&format!("{}: {}\n\n", message.role, text));
+    }
+
+    prompt.push_str("assistant:");
+    prompt
+}
+
+// Chat-style content parts renamed to their Responses API equivalents
+fn responses_content(content: &Value) -> Value {
+    let Some(parts) = content.as_array() else {
+        return content.clone();
+    };
+    Value::Array(
+        parts
+            .iter()
+            .map(|part| match part.get("type").and_then(|t| t.as_str()) {
+                Some("text") => serde_json::json!({ "type": "input_text", "text": part["text"] }),
+                Some("image_url") => serde_json::json!({
+                    "type": "input_image",
+                    "image_url": part["image_url"]["url"],
+                    "detail": part["image_url"].get("detail").cloned().unwrap_or(Value::from("auto")),
+                }),
+                Some("file") => {
+                    serde_json::json!({ "type": "input_file", "file_id": part["file"]["file_id"] })
+                }
+                _ => part.clone(),
+            })
+            .collect(),
+    )
+}
+
+fn parse_responses_output(output: &[Value], data: &Value) -> Result<Message, Error> {
+    let text: Vec<&str> = output
+        .iter()
+        .filter(|item| item["type"] == "message")
+        .flat_map(|item| item["content"].as_array().into_iter().flatten())
+        .filter(|part| part["type"] == "output_text")
+        .filter_map(|part| part["text"].as_str())
+        .collect();
+    let Some(id) = data["id"].as_str() else {
+        return Err(Error::UnexpectedResponse(data.clone()));
+    };
+    let usage = data.get("usage").map(|u| Usage {
+        prompt_tokens: u["input_tokens"].as_u64().unwrap_or(0),
+        completion_tokens: u["output_tokens"].as_u64().unwrap_or(0),
+    });
+
+    Ok(Message {
+        response_id: Some(id.to_string()),
+        usage,
+        ..Message::new("assistant", &text.join(""))
+    })
+}
+
+/// Check that every message has a role the chat API accepts.
+pub fn check_roles(messages: &[Message]) -> Result<(), Error> {
+    match messages
+        .iter()
+        .position(|m| !ALLOWED_ROLES.contains(&m.role.as_str()))
+    {
+        Some(index) => Err(Error::InvalidRole(index, messages[index].role.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Interleave two threads by timestamp, dropping identical adjacent messages.
+///
+/// A message without a timestamp can't be placed in time, so it keeps its
+/// append order: `a`'s messages come first until both heads are timestamped.
+pub fn merge_by_timestamp(a: &[Message], b: &[Message]) -> Vec<Message> {
+    let (mut a, mut b) = (a.iter().peekable(), b.iter().peekable());
+    let mut merged: Vec<Message> = Vec::new();
+
+    loop {
+        let take_a = match (a.peek(), b.peek()) {
+            (None, None) => break,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(x), Some(y)) => match (x.timestamp, y.timestamp) {
+                (Some(tx), Some(ty)) => tx <= ty,
+                _ => true,
+            },
+        };
+        let next = if take_a { a.next() } else { b.next() }.unwrap();
+        let duplicate = merged
+            .last()
+            .is_some_and(|last| last.role == next.role && last.content == next.content);
+        if !duplicate {
+            merged.push(next.clone());
+        }
+    }
+
+    merged
+}
+
+/// Parse `user=human,assistant=assistant` into a role map.
+pub fn parse_role_map(spec: &str) -> Result<HashMap<String, String>, String> {
+    let mut role_map = HashMap::new();
+
+    for pair in spec.split(',').filter(|p| !p.trim().is_empty()) {
+        let (from, to) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("expected ROLE=NAME, got '{}'", pair))?;
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() {
+            return Err(format!("expected ROLE=NAME, got '{}'", pair));
+        }
+        role_map.insert(from.to_string(), to.to_string());
+    }
+
+    Ok(role_map)
+}
+
+/// Parse a dotenv-style file: `KEY=value` lines, `#` comments and an optional `export `.
+pub fn parse_env_file(data: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+
+    for (number, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=VALUE", number + 1))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!(
+                "line {}: invalid variable name '{}'",
+                number + 1,
+                key
+            ));
+        }
+        let value = value.trim();
+        // Quotes only group the value; they are not part of it
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|&q| value.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+            .unwrap_or(value);
+        vars.push((key.to_string(), value.to_string()));
+    }
+
+    Ok(vars)
+}
+
+/// Drop markdown syntax so a reply pastes cleanly as plain text.
+pub fn strip_markdown(text: &str) -> String {
+    let link = regex::Regex::new(r"\[([^\]]*)\]\(([^)]*)\)").unwrap();
+    let emphasis = regex::Regex::new(r"(\*\*|__|`)").unwrap();
+
+    let lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| {
+            // Headings and quotes lose their markers; indentation is kept otherwise
+            let line = if line.starts_with('#') {
+                line.trim_start_matches('#').trim_start()
+            } else {
+                line.strip_prefix("> ").unwrap_or(line)
+            };
+            let line = link.replace_all(line, "$1 ($2)");
+            emphasis.replace_all(&line, "").into_owned()
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Keep only enough of the key to tell keys apart.
+pub fn redact_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 12 {
+        return "*".repeat(chars.len());
+    }
+    format!(
+        "{}...{}",
+        chars[..3].iter().collect::<String>(),
+        chars[chars.len() - 4..].iter().collect::<String>()
+    )
+}
@@ -1,40 +1,483 @@
+use ask_rs::agent::{
+    command_feedback, cut_after_first_command, parse_agent_json, parse_agent_response,
+    strip_command_echo, AgentAction,
+};
+use ask_rs::config::{config_path, load_config, set_config_value, Config};
+use ask_rs::{
+    append_content_part, append_text, check_roles, context_window, emits_thinking_tags,
+    estimate_tokens, flatten_messages, is_reasoning_model, merge_by_timestamp, parse_env_file,
+    parse_role_map, prepend_text, redact_key, seed_role, strip_markdown, strip_thinking_tags,
+    supports_json_mode, supports_json_schema, supports_prediction, Client, ClientConfig,
+    ConversationState, Message, RateLimit, Reply, TranscriptFormat, DEFAULT_BASE_URL,
+    DEFAULT_MODEL, DEFAULT_TEMPERATURE, KNOWN_MODELS, MINIMAL_SEED_PROMPT,
+};
 use atty::Stream;
+use base64::Engine;
 use clap::{Arg, ArgAction, Command};
 use dialoguer::{theme::ColorfulTheme, Select};
-use serde::{Deserialize, Serialize};
+use jsonschema::JSONSchema;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use regex::{Regex, RegexBuilder};
 use serde_json::Value;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
 use std::env;
 use std::fs;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::process;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command as ProcessCommand;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::SyntaxSet;
+
+// Set once from --loud-errors; read by every error path
+static LOUD_ERRORS: AtomicBool = AtomicBool::new(false);
 
-const MODEL: &str = "o1-mini";
-const HOST: &str = "api.openai.com";
-const ENDPOINT: &str = "/v1/chat/completions";
-const MAX_TOKENS: u32 = 2048;
-const TEMPERATURE: f64 = 0.6;
 const VISION_DETAIL: &str = "high";
 const TRANSCRIPT_NAME: &str = "gpt_transcript-";
-const CLIPBOARD_COMMAND_XORG: &str = "xclip -selection clipboard -t image/png -o";
+const CLIPBOARD_COMMAND_XORG: &str = "xclip -selection {selection} -t image/png -o";
 const CLIPBOARD_COMMAND_WAYLAND: &str = "wl-paste";
 const CLIPBOARD_COMMAND_UNSUPPORTED: &str = "UNSUPPORTED";
+const CLIPBOARD_TEXT_XORG: &str = "xclip -selection {selection} -o";
+const CLIPBOARD_TEXT_WAYLAND: &str = "wl-paste --no-newline";
+const CLIPBOARD_TEXT_MACOS: &str = "pbpaste";
+const CLIPBOARD_COPY_XORG: &str = "xclip -selection {selection}";
+const CLIPBOARD_COPY_WAYLAND: &str = "wl-copy";
+const DRY_RUN_MAX_STEPS: usize = 10;
+const CLEAR_CONFIRM_MESSAGES: usize = 4;
+const CONFIRM_PREVIEW_CHARS: usize = 500;
+const RATE_LIMIT_WARN_FRACTION: f64 = 0.1;
+const PIPED_BANNER_WIDTH: usize = 3;
+const SPINNER_DOTS: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const SPINNER_LINE: &[&str] = &["─", "╲", "│", "╱"];
+const SPINNER_ASCII: &[&str] = &["-", "\\", "|", "/"];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+const CONTEXT_POLL: Duration = Duration::from_millis(50);
+const WATCH_POLL: Duration = Duration::from_millis(500);
+// Editors often write a file in several steps; wait for it to settle
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+// Overrides the seed's "be concise" for a single turn
+const EXPLAIN_INSTRUCTION: &str = "For this answer only, ignore any instruction to be concise: explain thoroughly and step by step, as if teaching, with the reasoning behind each step.";
+const HTML_CODE_THEME: &str = "InspiredGitHub";
+const HTML_STYLE: &str =
+    "body{font-family:sans-serif;max-width:50em;margin:2em auto;padding:0 1em}\
+section{border-left:4px solid #ccc;padding:0 1em;margin:1.5em 0}\
+section.user{border-color:#4a90d9}section.assistant{border-color:#5cb85c}\
+section.system{border-color:#999;color:#555}\
+pre{padding:0.8em;overflow-x:auto}img{max-width:100%}p.cost{font-size:small;color:#777}";
+// Titling is a throwaway request, so default to a cheap model
+const TITLE_MODEL: &str = "gpt-4o-mini";
+
+// Per-invocation options for the CLI's own request handling
+#[derive(Debug, Default)]
+struct RequestOptions {
+    timing: bool,
+    verbose: bool,
+    retry_empty: bool,
+    prepend_system: Option<String>,
+    prompt_suffix: Option<String>,
+    // Ask for a step-by-step answer on the turn being sent
+    explain: bool,
+    show_request_id: bool,
+    // Show replies through a pager when stdout is a terminal
+    pager: bool,
+    // Drop <think> blocks from what is printed, and also from what is saved
+    strip_thinking_shown: bool,
+    strip_thinking_stored: bool,
+    // Print what was left out of each request and the token estimate
+    show_trim: bool,
+    // Also write each reply to this FIFO, and with fifo_only not to stdout
+    fifo: Option<PathBuf>,
+    fifo_only: bool,
+    // Print at most this many lines of each reply
+    output_lines: Option<usize>,
+    // Prompts, pickers and editors are only used when this is set
+    interactive: bool,
+    // Frames drawn on stderr while waiting for a reply; None draws nothing
+    spinner: Option<&'static [&'static str]>,
+    // Warn when the estimated prompt fills this much of the context window; 0 disables
+    context_warn_percent: u8,
+    // Send only the messages up to this index along with the new turn
+    from_message: Option<usize>,
+    // API calls made so far, retries included, and the most allowed in this run
+    api_calls: Cell<usize>,
+    call_budget: Option<usize>,
+    // Agent text protocol: keep only the first COMMAND: of each reply
+    cut_second_command: bool,
+    // Review each prompt before it is sent, in watch mode too
+    confirm_each: bool,
+    // Agent mode only: keep consecutive calls at least this far apart
+    min_interval: Option<Duration>,
+    last_call: Cell<Option<Instant>>,
+    // Time spent waiting on the API, for --timing; pacing sleeps are not counted
+    request_time: Cell<Duration>,
+}
+
+impl RequestOptions {
+    fn budget_exhausted(&self) -> bool {
+        self.call_budget
+            .is_some_and(|budget| self.api_calls.get() >= budget)
+    }
+
+    // Every send goes through here so the budget sees retries too
+    fn send(&self, client: &Client, messages: &[Message]) -> Result<Reply, ask_rs::Error> {
+        self.api_calls.set(self.api_calls.get() + 1);
+        if let (Some(interval), Some(last)) = (self.min_interval, self.last_call.get()) {
+            thread::sleep(interval.saturating_sub(last.elapsed()));
+        }
+        let started = Instant::now();
+        self.last_call.set(Some(started));
+        let result = self.send_now(client, messages);
+        self.request_time
+            .set(self.request_time.get() + started.elapsed());
+        result
+    }
+
+    fn send_now(&self, client: &Client, messages: &[Message]) -> Result<Reply, ask_rs::Error> {
+        let Some(frames) = self.spinner else {
+            return client.send_detailed(messages);
+        };
+
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for frame in frames.iter().cycle() {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    eprint!("\r{} ", frame);
+                    thread::sleep(SPINNER_INTERVAL);
+                }
+                eprint!("\r  \r");
+            });
+            let result = client.send_detailed(messages);
+            done.store(true, Ordering::Relaxed);
+            result
+        })
+    }
+}
+
+// Options that only apply to the recursive agent loop
+#[derive(Debug, Default)]
+struct AgentOptions {
+    stop_on: Option<Regex>,
+    // Commands matching any of these are refused before confirmation, even with --yes
+    deny_commands: Vec<Regex>,
+    // Extra variables for executed commands only, from --env-file
+    env: Vec<(String, String)>,
+    dry_run: bool,
+    max_steps: Option<usize>,
+    quiet: bool,
+    assume_yes: bool,
+    // Structured JSON replies instead of COMMAND:/DONE markers
+    json_protocol: bool,
+    // Skip step output identical to the previous step's
+    only_on_change: bool,
+    // Feed back output without echoed commands or empty sections
+    completion_only: bool,
+    // Offer to edit a proposed command in $EDITOR before running it
+    edit_commands: bool,
+    // Give up after this many replies in a row without a command
+    max_no_command: usize,
+}
+
+// The environment wins over a key saved by --setup
+fn get_api_key(config: &Config) -> String {
+    env::var("OPENAI_API_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .or(config.api_key.clone())
+        .unwrap_or_default()
+}
+
+// Several keys to rotate through: --api-key flags, then OPENAI_API_KEYS, then the single key
+fn get_api_keys<'a>(
+    config: &Config,
+    flags: Option<impl Iterator<Item = &'a String>>,
+) -> Vec<String> {
+    let split = |keys: &str| -> Vec<String> {
+        keys.split(',')
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect()
+    };
+
+    if let Some(flags) = flags {
+        return flags.flat_map(|keys| split(keys)).collect();
+    }
+    if let Some(keys) = env::var("OPENAI_API_KEYS").ok().map(|keys| split(&keys)) {
+        if !keys.is_empty() {
+            return keys;
+        }
+    }
+    let key = get_api_key(config);
+    if key.is_empty() {
+        Vec::new()
+    } else {
+        vec![key]
+    }
+}
+
+// Ask for the key, default model and temperature, and save them to the config file
+fn run_setup(config: &Config) -> Config {
+    let theme = ColorfulTheme::default();
+
+    let api_key = dialoguer::Password::with_theme(&theme)
+        .with_prompt("OpenAI API key")
+        .interact()
+        .unwrap_or_else(|e| fail("Setup aborted", Some(&e)));
+
+    let mut client_config = ClientConfig::new(&api_key, DEFAULT_MODEL);
+    if let Some(base_url) = env::var("OPENAI_BASE_URL").ok().or(config.base_url.clone()) {
+        client_config.base_url = base_url.trim_end_matches('/').to_string();
+    }
+    let current = config.model.as_deref().unwrap_or(DEFAULT_MODEL);
+    let model = match Client::new(client_config).list_models() {
+        Ok(mut models) if !models.is_empty() => {
+            models.sort();
+            let selection = Select::with_theme(&theme)
+                .with_prompt("Default model")
+                .default(models.iter().position(|m| m == current).unwrap_or(0))
+                .items(&models)
+                .interact()
+                .unwrap_or_else(|e| fail("Setup aborted", Some(&e)));
+            models.swap_remove(selection)
+        }
+        Ok(_) => current.to_string(),
+        Err(e) => fail("Setup aborted: the key could not list models", Some(&e)),
+    };
+
+    let temperature = dialoguer::Input::<f64>::with_theme(&theme)
+        .with_prompt("Default temperature")
+        .default(config.temperature.unwrap_or(DEFAULT_TEMPERATURE))
+        .validate_with(|t: &f64| {
+            if (0.0..=2.0).contains(t) {
+                Ok(())
+            } else {
+                Err("Temperature must be between 0 and 2.")
+            }
+        })
+        .interact_text()
+        .unwrap_or_else(|e| fail("Setup aborted", Some(&e)));
+
+    let mut path = None;
+    for (key, value) in [
+        ("api_key", toml::Value::String(api_key.clone())),
+        ("model", toml::Value::String(model.clone())),
+        ("temperature", toml::Value::Float(temperature)),
+    ] {
+        path = Some(
+            set_config_value(key, value)
+                .unwrap_or_else(|e| fail(&format!("Setup aborted: {}", e), None)),
+        );
+    }
+    println!("Settings saved to {}", path.unwrap().display());
+
+    load_config().unwrap_or_else(|e| fail(&format!("Setup aborted: {}", e), None))
+}
+
+// Both ends must be a terminal: a prompt on a pipe either hangs or garbles the output
+fn is_interactive(force: bool) -> bool {
+    force || (atty::is(Stream::Stdin) && atty::is(Stream::Stdout))
+}
+
+// Unicode only when the locale says the terminal can show it
+fn spinner_frames(style: Option<&str>, disabled: bool) -> Option<&'static [&'static str]> {
+    if disabled || !atty::is(Stream::Stderr) {
+        return None;
+    }
+    let unicode = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|key| env::var(key).ok().filter(|v| !v.is_empty()))
+        .is_some_and(|locale| {
+            let locale = locale.to_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        });
+
+    Some(match style {
+        Some("dots") => SPINNER_DOTS,
+        Some("line") => SPINNER_LINE,
+        Some(_) => SPINNER_ASCII,
+        None if unicode => SPINNER_DOTS,
+        None => SPINNER_ASCII,
+    })
+}
+
+fn check_key(client: &Client) {
+    println!("Base URL: {}", client.config().base_url);
+    println!("API key: {}", redact_key(&client.config().api_key));
+
+    match client.list_models() {
+        Ok(_) => println!("Key is valid."),
+        // Only an auth failure says anything about the key itself
+        Err(e @ ask_rs::Error::Status(status))
+            if status == reqwest::StatusCode::UNAUTHORIZED
+                || status == reqwest::StatusCode::FORBIDDEN =>
+        {
+            fail("Key was rejected", Some(&e))
+        }
+        Err(e) => fail("Connection error, the key could not be checked", Some(&e)),
+    }
+}
+
+fn set_default_model(client: &Client, model: &str) {
+    match client.list_models() {
+        Ok(models) if !models.iter().any(|m| m == model) => fail(
+            &format!("Unknown model '{}'. Not saving it as default.", model),
+            None,
+        ),
+        Ok(_) => {}
+        Err(e) => report_error("Could not validate model against the model list", Some(&e)),
+    }
+
+    match set_config_value("model", toml::Value::String(model.to_string())) {
+        Ok(path) => println!("Default model set to {} in {}", model, path.display()),
+        Err(e) => fail(&format!("Unable to write config file: {}", e), None),
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)] // Added Clone here
-struct Message {
-    role: String,
-    content: Value,
+// Everything the request would use after flags, env and config file are merged
+fn dump_config(
+    client_config: &ClientConfig,
+    config: &Config,
+    options: &RequestOptions,
+    transcript_path: &Path,
+) {
+    // Destructured without `..`, so a new ClientConfig field can't be left out of the dump
+    let ClientConfig {
+        api_key,
+        base_url,
+        model,
+        temperature,
+        max_tokens,
+        user,
+        flatten,
+        role_map,
+        debug_dump_dir,
+        prediction,
+        stop,
+        json_mode,
+        alternate_roles,
+        offline,
+        json_schema,
+        strict_roles,
+        responses_api,
+        fallback_keys,
+        save_request,
+    } = client_config;
+    let resolved = serde_json::json!({
+        "config_file": config_path().filter(|p| p.exists()),
+        "transcript": transcript_path,
+        "transcript_template": config.transcript_template,
+        "api_key": redact_key(api_key),
+        "fallback_keys": fallback_keys.iter().map(|k| redact_key(k)).collect::<Vec<String>>(),
+        "base_url": base_url,
+        "model": model,
+        "temperature": temperature,
+        "max_tokens": max_tokens,
+        "user": user,
+        "flatten": flatten,
+        "alternate_roles": alternate_roles,
+        "strict_roles": strict_roles,
+        "role_map": role_map,
+        "offline": offline,
+        "responses_api": responses_api,
+        "stop": stop,
+        "json_mode": json_mode,
+        "prediction": prediction,
+        "json_schema": json_schema,
+        "save_request": save_request,
+        "debug_dump_dir": debug_dump_dir,
+        "prompt_suffix": options.prompt_suffix,
+    });
+    println!("{}", serde_json::to_string_pretty(&resolved).unwrap());
+}
+
+// Notes come from the same checks that pick the request shape, so they can't drift
+fn list_models_local() {
+    let width = KNOWN_MODELS
+        .iter()
+        .map(|(_, id)| id.len())
+        .max()
+        .unwrap_or(0);
+    let mut provider = "";
+    for &(owner, id) in KNOWN_MODELS {
+        if owner != provider {
+            println!("{}{}:", if provider.is_empty() { "" } else { "\n" }, owner);
+            provider = owner;
+        }
+        let mut notes = vec![if is_reasoning_model(id) {
+            "reasoning, no temperature"
+        } else {
+            "chat, temperature"
+        }];
+        if supports_json_schema(id) {
+            notes.push("structured outputs");
+        } else if supports_json_mode(id) {
+            notes.push("JSON mode");
+        }
+        if supports_prediction(id) {
+            notes.push("predicted outputs");
+        }
+        let window = context_window(id).map(|tokens| format!(", {}k context", tokens / 1000));
+        println!(
+            "  {:width$}  {}{}",
+            id,
+            notes.join(", "),
+            window.unwrap_or_default(),
+            width = width
+        );
+    }
+    println!("\nOther providers' ids work too with a matching base_url or [routes] entry.");
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ConversationState {
-    model: String,
-    messages: Vec<Message>,
+// Let the user choose from the provider's models and remember the choice in the config
+fn pick_model(client: &Client, current: &str) -> Option<String> {
+    let mut models = match client.list_models() {
+        Ok(models) if !models.is_empty() => models,
+        Ok(_) => return None,
+        Err(e) => {
+            report_error("Could not fetch the model list", Some(&e));
+            return None;
+        }
+    };
+    models.sort();
+
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select a model")
+        .default(models.iter().position(|m| m == current).unwrap_or(0))
+        .items(&models)
+        .interact()
+        .ok()?;
+    let model = models.swap_remove(selection);
+
+    match set_config_value("model", toml::Value::String(model.clone())) {
+        Ok(path) => println!("Default model set to {} in {}", model, path.display()),
+        Err(e) => report_error(&format!("Unable to write config file: {}", e), None),
+    }
+    Some(model)
 }
 
-fn get_api_key() -> String {
-    env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set")
+fn set_default_temperature(temperature: f64) {
+    if !(0.0..=2.0).contains(&temperature) {
+        fail("Temperature must be between 0 and 2.", None);
+    }
+
+    match set_config_value("temperature", toml::Value::Float(temperature)) {
+        Ok(path) => println!(
+            "Default temperature set to {} in {}",
+            temperature,
+            path.display()
+        ),
+        Err(e) => fail(&format!("Unable to write config file: {}", e), None),
+    }
 }
 
 fn main() {
@@ -51,6 +494,25 @@ fn main() {
                 .help("Push image from clipboard into pipeline")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("clip")
+                .long("clip")
+                .help("Use the clipboard's text as the prompt; arguments are appended to it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("selection")
+                .long("selection")
+                .value_parser(["clipboard", "primary"])
+                .default_value("clipboard")
+                .help("X11 selection to read the image from"),
+        )
+        .arg(
+            Arg::new("clipboard-cmd")
+                .long("clipboard-cmd")
+                .value_name("COMMAND")
+                .help("Shell command that prints the clipboard image, instead of detecting xclip/wl-paste"),
+        )
         .arg(
             Arg::new("manage")
                 .short('o')
@@ -75,331 +537,2984 @@ fn main() {
                 .help("Interactive agent mode")
                 .action(ArgAction::SetTrue),
         )
-        .get_matches();
-
-    let api_key = get_api_key();
-    if api_key.is_empty() {
-        eprintln!("Missing API key! Set the OPENAI_API_KEY environment variable and try again.");
-        std::process::exit(1);
-    }
-
-    let temp_dir = env::temp_dir();
-    let transcript_path = temp_dir.join(format!("{}{}", TRANSCRIPT_NAME, process::parent_id()));
-
-    let mut conversation_state = if transcript_path.exists() {
-        let data = fs::read_to_string(&transcript_path).expect("Unable to read transcript file");
-        serde_json::from_str(&data).expect("Unable to parse transcript JSON")
-    } else {
-        let initial_message = Message {
-            role: if MODEL.contains("o1-") {
-                "user".to_string()
-            } else {
-                "system".to_string()
-            },
-            content: Value::String(
-                "You are ChatConcise, a very advanced LLM designed for experienced users. As ChatConcise you oblige to adhere to the following directives UNLESS overridden by the user:\nBe concise, proactive, helpful and efficient. Do not say anything more than what needed, but also, DON'T BE LAZY. Provide ONLY code when an implementation is needed. DO NOT USE MARKDOWN.".to_string(),
-            ),
-        };
-        ConversationState {
-            model: MODEL.to_string(),
-            messages: vec![initial_message],
-        }
-    };
-
-    // Determine if input is being piped and get full input
-    let input = if !atty::is(Stream::Stdin) {
-        // Read from stdin
-        let mut buffer = String::new();
-        io::stdin()
-            .read_to_string(&mut buffer)
-            .expect("Failed to read from stdin");
-        if buffer.trim().is_empty() {
-            Value::Null
-        } else {
-            Value::String(buffer)
-        }
-    } else if let Some(values) = matches.get_many::<String>("input") {
-        let input_str = values
-            .map(|s| s.as_str()) // Convert &String to &str
-            .collect::<Vec<&str>>() // Collect into Vec<&str>
-            .join(" "); // Join with spaces
-        if input_str.trim().is_empty() {
-            Value::Null
-        } else {
-            Value::String(input_str)
+        .arg(
+            Arg::new("name")
+                .long("name")
+                .value_name("NAME")
+                .help("Use the named conversation instead of the one tied to this shell"),
+        )
+        .arg(
+            Arg::new("ensure-conversation")
+                .long("ensure-conversation")
+                .requires("name")
+                .help("Create the --name conversation with just its seed if it doesn't exist, then exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("resume-prefix")
+                .long("resume-prefix")
+                .value_name("PREFIX")
+                .conflicts_with("name")
+                .help("Continue the latest conversation whose name starts with PREFIX, or start one"),
+        )
+        .arg(
+            Arg::new("model")
+                .long("model")
+                .value_name("NAME")
+                .help("Model for new conversations (overrides the config file)"),
+        )
+        .arg(
+            Arg::new("persona")
+                .long("persona")
+                .value_name("NAME")
+                .help("Start the conversation from a persona defined in the config"),
+        )
+        .arg(
+            Arg::new("switch-model")
+                .long("switch-model")
+                .value_name("NAME")
+                .help("Continue the current conversation with another model"),
+        )
+        .arg(
+            Arg::new("reset-on-model-change")
+                .long("reset-on-model-change")
+                .requires("switch-model")
+                .help("With --switch-model, start over from the seed instead of keeping old turns")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("temperature")
+                .long("temperature")
+                .value_name("T")
+                .value_parser(clap::value_parser!(f64))
+                .help("Sampling temperature for this run (new conversations remember it)"),
+        )
+        .arg(
+            Arg::new("pick-model")
+                .long("pick-model")
+                .help("Choose the default model from the provider's list and save it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("yes")
+                .long("yes")
+                .short('y')
+                .help("Skip confirmation prompts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("flatten")
+                .long("flatten")
+                .help("Send the conversation as a single prompt to the completions endpoint")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("server-state")
+                .long("server-state")
+                .help("Let OpenAI keep the conversation and send only new turns (Responses API)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict-roles")
+                .long("strict-roles")
+                .value_name("BOOL")
+                .value_parser(clap::value_parser!(bool))
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_value("true")
+                .default_missing_value("true")
+                .help("Refuse to send messages with unknown roles; --strict-roles=false relaxes it"),
+        )
+        .arg(
+            Arg::new("alternate-roles")
+                .long("alternate-roles")
+                .help("Merge consecutive same-role messages for backends that require alternating turns")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("timing")
+                .long("timing")
+                .help("Print API call latency to stderr")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("role-map")
+                .long("role-map")
+                .value_name("MAP")
+                .help("Rename roles for the backend, e.g. user=human,assistant=assistant"),
+        )
+        .arg(
+            Arg::new("no-network")
+                .long("no-network")
+                .help("Refuse to send anything; history, -l and exports still work (also ASK_OFFLINE=1)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("setup")
+                .long("setup")
+                .help("Interactively save an API key, default model and temperature")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("list-models-local")
+                .long("list-models-local")
+                .help("List common model ids and how ask treats them, without contacting the API")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("api-key")
+                .long("api-key")
+                .value_name("KEY")
+                .action(ArgAction::Append)
+                .help("API key to use; repeat to rotate to the next key when rate limited"),
+        )
+        .arg(
+            Arg::new("check-key")
+                .long("check-key")
+                .help("Validate the API key against the provider and exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("set-default-model")
+                .long("set-default-model")
+                .value_name("NAME")
+                .help("Save the default model for new conversations to the config file"),
+        )
+        .arg(
+            Arg::new("set-default-temperature")
+                .long("set-default-temperature")
+                .value_name("TEMPERATURE")
+                .value_parser(clap::value_parser!(f64))
+                .help("Save the default temperature to the config file"),
+        )
+        .arg(
+            Arg::new("minimal-seed")
+                .long("minimal-seed")
+                .help("Seed new o-series conversations with a one-line instruction instead of the persona")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("upload-file")
+                .long("upload-file")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Upload a file through the provider's Files API and attach it to this turn"),
+        )
+        .arg(
+            Arg::new("diff-transcript")
+                .long("diff-transcript")
+                .num_args(2)
+                .value_names(["A", "B"])
+                .help("Compare the assistant replies of two saved conversations"),
+        )
+        .arg(
+            Arg::new("diff-prompts")
+                .long("diff-prompts")
+                .help("Include user prompts in --diff-transcript")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stdin-only")
+                .long("stdin-only")
+                .help("Fail instead of opening the history viewer when there is no input")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .help("Print diagnostics to stderr")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("retry-empty")
+                .long("retry-empty")
+                .help("Re-request once when the model returns an empty reply")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("transcript-format")
+                .long("transcript-format")
+                .value_parser(["json", "yaml"])
+                .default_value("json")
+                .help("On-disk format of the current conversation's transcript"),
+        )
+        .arg(
+            Arg::new("history-format")
+                .long("history-format")
+                .value_name("FORMAT")
+                .value_parser(["plain", "markdown", "json"])
+                .default_value("plain")
+                .help("How the history view renders the conversation"),
+        )
+        .arg(
+            Arg::new("strip-thinking-tags")
+                .long("strip-thinking-tags")
+                .value_name("WHERE")
+                .value_parser(["display", "transcript", "off"])
+                .num_args(0..=1)
+                .default_missing_value("display")
+                .help("Remove <think> blocks from printed replies, or also from the transcript (on for display with reasoning models)"),
+        )
+        .arg(
+            Arg::new("history-grep")
+                .long("history-grep")
+                .value_name("PATTERN")
+                .help("Show only the messages containing PATTERN (case-insensitive) in the history view"),
+        )
+        .arg(
+            Arg::new("history-regex")
+                .long("history-regex")
+                .requires("history-grep")
+                .help("Treat the --history-grep pattern as a regular expression")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pin-last")
+                .long("pin-last")
+                .help("Pin the last message so context management never drops it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("export-jsonl")
+                .long("export-jsonl")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Append the conversation to a fine-tuning JSONL file"),
+        )
+        .arg(
+            Arg::new("with-costs")
+                .long("with-costs")
+                .help("Add token usage and estimated cost under each reply in Markdown/HTML output")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("export-html")
+                .long("export-html")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the conversation to a standalone HTML file"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .help("Agent mode: show proposed commands without executing them")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max-steps")
+                .long("max-steps")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Agent mode: stop after N proposed commands (defaults to 10 with --dry-run)"),
+        )
+        .arg(
+            Arg::new("min-interval")
+                .long("min-interval")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(f64))
+                .help("Wait at least SECS seconds between API calls in agent mode"),
+        )
+        .arg(
+            Arg::new("max-no-command")
+                .long("max-no-command")
+                .alias("reask-on-no-command")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("3")
+                .help("Stop agent mode after N replies in a row without a command"),
+        )
+        .arg(
+            Arg::new("agent-call-budget")
+                .long("agent-call-budget")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Agent mode: stop after N API calls in total, retries included"),
+        )
+        .arg(
+            Arg::new("prepend-system")
+                .long("prepend-system")
+                .value_name("TEXT")
+                .help("Add a system instruction after the seed for this request only"),
+        )
+        .arg(
+            Arg::new("quiet-agent")
+                .long("quiet-agent")
+                .help("Agent mode: show a one-line status per command instead of its output")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("from-message")
+                .long("from-message")
+                .value_name("INDEX")
+                .value_parser(clap::value_parser!(usize))
+                .help("Send only messages 0..=INDEX as context for this prompt (0 is the seed)"),
+        )
+        .arg(
+            Arg::new("truncate")
+                .long("truncate")
+                .requires("from-message")
+                .help("With --from-message, also delete the later unpinned messages from the transcript")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("show-trim")
+                .long("show-trim")
+                .help("Print which messages are left out of the request and the estimated tokens")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("json-schema")
+                .long("json-schema")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Request structured output following the JSON Schema in FILE and validate the reply"),
+        )
+        .arg(
+            Arg::new("explain")
+                .long("explain")
+                .help("Ask for a thorough, step-by-step explanation for this prompt only")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("suffix")
+                .long("suffix")
+                .value_name("TEXT")
+                .help("Append TEXT to the prompt when sending it (overrides prompt_suffix in the config)"),
+        )
+        .arg(
+            Arg::new("input-prefix")
+                .long("input-prefix")
+                .value_name("TEXT")
+                .help("Put TEXT before the prompt, e.g. \"Translate to Spanish:\""),
+        )
+        .arg(
+            Arg::new("input-suffix")
+                .long("input-suffix")
+                .value_name("TEXT")
+                .help("Put TEXT after the prompt"),
+        )
+        .arg(
+            Arg::new("context-from-command")
+                .long("context-from-command")
+                .value_name("CMD")
+                .help("Run CMD once and add its output to the prompt, e.g. \"git diff\""),
+        )
+        .arg(
+            Arg::new("context-timeout")
+                .long("context-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("30")
+                .help("Give up on --context-from-command after SECS seconds"),
+        )
+        .arg(
+            Arg::new("predict")
+                .long("predict")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Send the file as a predicted output to speed up edit-style replies"),
+        )
+        .arg(
+            Arg::new("confirm-each")
+                .long("confirm-each")
+                .help("Show the prompt and ask for confirmation before sending it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("save-request")
+                .long("save-request")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the exact request to PATH (key redacted) before sending it"),
+        )
+        .arg(
+            Arg::new("send-request")
+                .long("send-request")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("save-request")
+                .help("Send a request saved by --save-request verbatim and print the raw response"),
+        )
+        .arg(
+            Arg::new("show-request-id")
+                .long("show-request-id")
+                .help("Print the provider's request id to stderr after each call")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("spinner-style")
+                .long("spinner-style")
+                .value_name("STYLE")
+                .value_parser(["dots", "line", "ascii"])
+                .help("Wait indicator to draw on stderr (default: dots on UTF-8 locales, ascii otherwise)"),
+        )
+        .arg(
+            Arg::new("no-spinner")
+                .long("no-spinner")
+                .help("Don't draw a wait indicator")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("limit-output-lines")
+                .long("limit-output-lines")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Print only the first N lines of a reply; the transcript keeps all of it"),
+        )
+        .arg(
+            Arg::new("fifo")
+                .long("fifo")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Also write each reply to the named pipe at PATH"),
+        )
+        .arg(
+            Arg::new("fifo-only")
+                .long("fifo-only")
+                .requires("fifo")
+                .help("Write replies only to --fifo, not to stdout")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pager")
+                .long("pager")
+                .help("Show replies through $PAGER (or less -R) when writing to a terminal")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("batch")
+                .long("batch")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Send each line of FILE as its own single-turn conversation"),
+        )
+        .arg(
+            Arg::new("batch-concurrency")
+                .long("batch-concurrency")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("4")
+                .help("Maximum number of --batch requests in flight"),
+        )
+        .arg(
+            Arg::new("batch-output-dir")
+                .long("batch-output-dir")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write each --batch reply to DIR/<line>.txt instead of stdout"),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Re-send the prompt with PATH's contents every time PATH changes"),
+        )
+        .arg(
+            Arg::new("watch-append")
+                .long("watch-append")
+                .requires("watch")
+                .help("Keep every --watch run in the conversation instead of replacing the last one")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stop-on")
+                .long("stop-on")
+                .alias("interrupt-agent-on")
+                .value_name("REGEX")
+                .help("Stop agent mode when a command's output matches the pattern"),
+        )
+        .arg(
+            Arg::new("deny-commands")
+                .long("deny-commands")
+                .value_name("REGEX")
+                .action(ArgAction::Append)
+                .help("Refuse agent commands matching REGEX without asking (repeatable)"),
+        )
+        .arg(
+            Arg::new("env-file")
+                .long("env-file")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Set the variables from a dotenv file for commands run by agent mode"),
+        )
+        .arg(
+            Arg::new("regenerate")
+                .long("regenerate")
+                .help("Discard the last reply and ask the model again")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("samples")
+                .long("samples")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1")
+                .requires("regenerate")
+                .help("With --regenerate, request N alternatives and pick one to keep"),
+        )
+        .arg(
+            Arg::new("context-window-warn")
+                .long("context-window-warn")
+                .value_name("PERCENT")
+                .value_parser(clap::value_parser!(u8).range(0..=100))
+                .default_value("80")
+                .help("Warn when the conversation fills this much of the model's context window (0 disables)"),
+        )
+        .arg(
+            Arg::new("force-interactive")
+                .long("force-interactive")
+                .help("Keep prompts and editors on even when not attached to a terminal")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("title-auto")
+                .long("title-auto")
+                .help("Name the conversation with a cheap side-request after the first reply")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("copy")
+                .long("copy")
+                .help("Also copy the reply to the clipboard, with markdown stripped")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("loud-errors")
+                .long("loud-errors")
+                .overrides_with("quiet-errors")
+                .help("Print errors with their full cause chain and a backtrace")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet-errors")
+                .long("quiet-errors")
+                .overrides_with("loud-errors")
+                .help("Print errors as a single line (the default)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("trace")
+                .long("trace")
+                .help("Explain on stderr which mode was chosen and why")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dump-config")
+                .long("dump-config")
+                .help("Print the resolved settings as JSON and exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max-conversations")
+                .long("max-conversations")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("50")
+                .help("Manage mode: list only the N most recent conversations"),
+        )
+        .arg(
+            Arg::new("output-only-on-change")
+                .long("output-only-on-change")
+                .help("Agent mode: only print a step when its command or result differs from the last one")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("interrupt-to-edit")
+                .long("interrupt-to-edit")
+                .help("Add an edit choice to the agent's confirm prompt that opens the command in $EDITOR")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("completion-only")
+                .long("completion-only")
+                .help("Feed agent commands' output back without echoed commands or empty sections")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("think-out-loud")
+                .long("think-out-loud")
+                .help("Have the agent reply with JSON {thought, command, done} when the model supports it")
+                .action(ArgAction::SetTrue),
+        )
+        .get_matches();
+
+    LOUD_ERRORS.store(matches.get_flag("loud-errors"), Ordering::Relaxed);
+    let tracing = matches.get_flag("trace");
+    let trace = |step: &str| {
+        if tracing {
+            eprintln!("[trace] {}", step);
+        }
+    };
+
+    let mut config = load_config().unwrap_or_else(|e| fail(&e, None));
+    trace(&match config_path().filter(|p| p.exists()) {
+        Some(path) => format!("config loaded from {}", path.display()),
+        None => "no config file, using built-in defaults".to_string(),
+    });
+
+    let persona = matches.get_one::<String>("persona").map(|name| {
+        config
+            .persona(name)
+            .cloned()
+            .unwrap_or_else(|e| fail(&e, None))
+    });
+
+    let mut options = RequestOptions {
+        timing: matches.get_flag("timing"),
+        verbose: matches.get_flag("verbose"),
+        retry_empty: matches.get_flag("retry-empty"),
+        prepend_system: matches.get_one::<String>("prepend-system").cloned(),
+        prompt_suffix: matches
+            .get_one::<String>("suffix")
+            .cloned()
+            .or(config.prompt_suffix.clone()),
+        explain: matches.get_flag("explain"),
+        show_request_id: matches.get_flag("show-request-id"),
+        pager: matches.get_flag("pager"),
+        strip_thinking_shown: false,
+        strip_thinking_stored: false,
+        output_lines: matches.get_one::<usize>("limit-output-lines").copied(),
+        show_trim: matches.get_flag("show-trim"),
+        fifo: matches.get_one::<PathBuf>("fifo").cloned(),
+        fifo_only: matches.get_flag("fifo-only"),
+        interactive: is_interactive(matches.get_flag("force-interactive")),
+        spinner: spinner_frames(
+            matches
+                .get_one::<String>("spinner-style")
+                .map(|s| s.as_str()),
+            matches.get_flag("no-spinner"),
+        ),
+        context_warn_percent: *matches.get_one::<u8>("context-window-warn").unwrap(),
+        from_message: None,
+        api_calls: Cell::new(0),
+        call_budget: matches.get_one::<usize>("agent-call-budget").copied(),
+        cut_second_command: false,
+        confirm_each: matches.get_flag("confirm-each"),
+        min_interval: None,
+        last_call: Cell::new(None),
+        request_time: Cell::new(Duration::ZERO),
+    };
+
+    let mut agent_options = AgentOptions {
+        stop_on: matches.get_one::<String>("stop-on").map(|pattern| {
+            Regex::new(pattern).unwrap_or_else(|e| fail("Invalid --stop-on pattern", Some(&e)))
+        }),
+        deny_commands: matches
+            .get_many::<String>("deny-commands")
+            .into_iter()
+            .flatten()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .unwrap_or_else(|e| fail("Invalid --deny-commands pattern", Some(&e)))
+            })
+            .collect(),
+        env: matches
+            .get_one::<PathBuf>("env-file")
+            .map(|path| {
+                fs::read_to_string(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| parse_env_file(&data))
+                    .unwrap_or_else(|e| {
+                        fail(
+                            &format!("Unable to read --env-file {}: {}", path.display(), e),
+                            None,
+                        )
+                    })
+            })
+            .unwrap_or_default(),
+        dry_run: matches.get_flag("dry-run"),
+        // A dry run never changes anything, so make sure it can't spin forever
+        max_steps: matches
+            .get_one::<usize>("max-steps")
+            .copied()
+            .or(matches.get_flag("dry-run").then_some(DRY_RUN_MAX_STEPS)),
+        quiet: matches.get_flag("quiet-agent"),
+        assume_yes: matches.get_flag("yes"),
+        json_protocol: matches.get_flag("think-out-loud"),
+        only_on_change: matches.get_flag("output-only-on-change"),
+        completion_only: matches.get_flag("completion-only"),
+        edit_commands: matches.get_flag("interrupt-to-edit"),
+        max_no_command: *matches.get_one::<u64>("max-no-command").unwrap() as usize,
+    };
+
+    if let Some(mut paths) = matches.get_many::<String>("diff-transcript") {
+        let (a, b) = (paths.next().unwrap(), paths.next().unwrap());
+        trace("--diff-transcript given: diffing before anything needs the API key");
+        diff_transcripts(a, b, matches.get_flag("diff-prompts"));
+        return;
+    }
+
+    if matches.get_flag("list-models-local") {
+        trace("--list-models-local given: printing the built-in catalog");
+        list_models_local();
+        return;
+    }
+
+    let offline = matches.get_flag("no-network")
+        || env::var("ASK_OFFLINE").is_ok_and(|v| !v.is_empty() && v != "0");
+    trace(if offline {
+        "offline: requests fail, local operations still work"
+    } else {
+        "network access allowed"
+    });
+
+    // First run without a key or config: walk through setup instead of failing
+    let unconfigured =
+        !config_path().is_some_and(|p| p.exists()) && get_api_key(&config).is_empty();
+    if matches.get_flag("setup") || (unconfigured && options.interactive && !offline) {
+        trace("running the setup wizard");
+        config = run_setup(&config);
+        if matches.get_flag("setup") {
+            return;
+        }
+    }
+
+    let mut api_keys = get_api_keys(&config, matches.get_many::<String>("api-key"));
+    let api_key = if api_keys.is_empty() {
+        String::new()
+    } else {
+        api_keys.remove(0)
+    };
+    // Local operations don't need a key, and offline nothing else can run
+    if api_key.is_empty() && !offline && !matches.get_flag("ensure-conversation") {
+        fail(
+            "Missing API key! Set the OPENAI_API_KEY environment variable or run ask --setup.",
+            None,
+        );
+    }
+
+    let mut model = matches
+        .get_one::<String>("model")
+        .cloned()
+        .or(persona.as_ref().and_then(|p| p.model.clone()))
+        .or(config.model.clone())
+        .unwrap_or_else(|| DEFAULT_MODEL.to_string());
+    let mut client_config = ClientConfig::new(&api_key, &model);
+    client_config.fallback_keys = api_keys;
+    if matches
+        .get_one::<f64>("temperature")
+        .is_some_and(|t| !(0.0..=2.0).contains(t))
+    {
+        fail("Temperature must be between 0 and 2.", None);
+    }
+    client_config.temperature = matches
+        .get_one::<f64>("temperature")
+        .copied()
+        .or(persona.as_ref().and_then(|p| p.temperature))
+        .or(config.temperature)
+        .unwrap_or(DEFAULT_TEMPERATURE);
+    client_config.user = Some(whoami::username());
+    if let Some(base_url) = env::var("OPENAI_BASE_URL").ok().or(config.base_url.clone()) {
+        client_config.base_url = base_url.trim_end_matches('/').to_string();
+    }
+    client_config.flatten = matches.get_flag("flatten");
+    client_config.alternate_roles = matches.get_flag("alternate-roles");
+    client_config.offline = offline;
+    client_config.strict_roles = *matches.get_one::<bool>("strict-roles").unwrap();
+    client_config.debug_dump_dir = env::var_os("ASK_DEBUG_DUMP_DIR").map(PathBuf::from);
+    if let Some(spec) = matches.get_one::<String>("role-map") {
+        client_config.role_map = parse_role_map(spec)
+            .unwrap_or_else(|e| fail(&format!("Invalid --role-map: {}", e), None));
+    }
+
+    if matches.get_flag("check-key") {
+        trace("--check-key given: validating the key and exiting");
+        check_key(&Client::new(client_config));
+        return;
+    }
+
+    if matches.contains_id("set-default-model") || matches.contains_id("set-default-temperature") {
+        trace("--set-default-* given: updating the config file and exiting");
+        if let Some(model) = matches.get_one::<String>("set-default-model") {
+            set_default_model(&Client::new(client_config.clone()), model);
+        }
+        if let Some(temperature) = matches.get_one::<f64>("set-default-temperature") {
+            set_default_temperature(*temperature);
+        }
+        return;
+    }
+
+    let temp_dir = env::temp_dir();
+    let transcript_format = match matches
+        .get_one::<String>("transcript-format")
+        .unwrap()
+        .as_str()
+    {
+        "yaml" => TranscriptFormat::Yaml,
+        _ => TranscriptFormat::Json,
+    };
+    for key in ["name", "resume-prefix"] {
+        if matches
+            .get_one::<String>(key)
+            .is_some_and(|name| name.is_empty() || name.contains('/'))
+        {
+            fail(&format!("Invalid conversation name for --{}.", key), None);
+        }
+    }
+    if config
+        .transcript_template
+        .as_ref()
+        .is_some_and(|template| template.is_empty() || template.contains('/'))
+    {
+        fail(
+            "Invalid transcript_template in the config: it must be a non-empty file name.",
+            None,
+        );
+    }
+    // The template only replaces what follows the prefix, so -o and --diff-transcript still find it
+    let templated = |name: Option<&str>| {
+        config.transcript_template.as_ref().map(|template| {
+            let pid = process::parent_id().to_string();
+            temp_dir.join(format!(
+                "{}{}{}",
+                TRANSCRIPT_NAME,
+                template
+                    .replace("{pid}", &pid)
+                    .replace("{name}", name.unwrap_or(&pid))
+                    .replace("{date}", &format_date(unix_now()))
+                    .replace("{model}", &model.replace('/', "_")),
+                transcript_format.extension()
+            ))
+        })
+    };
+    let transcript_path = if let Some(name) = matches.get_one::<String>("name") {
+        trace(&format!("transcript chosen by --name {}", name));
+        if let Some(path) = config
+            .transcript_template
+            .as_ref()
+            .filter(|template| template.contains("{name}"))
+            .and_then(|_| templated(Some(name)))
+        {
+            path
+        } else {
+            temp_dir.join(format!(
+                "{}{}{}",
+                TRANSCRIPT_NAME,
+                name,
+                transcript_format.extension()
+            ))
+        }
+    } else if let Some(prefix) = matches.get_one::<String>("resume-prefix") {
+        trace(&format!(
+            "transcript chosen by --resume-prefix {}: latest match, or a new one",
+            prefix
+        ));
+        latest_transcript_with_prefix(&temp_dir, prefix).unwrap_or_else(|| {
+            temp_dir.join(format!(
+                "{}{}{}{}",
+                TRANSCRIPT_NAME,
+                prefix,
+                unix_now(),
+                transcript_format.extension()
+            ))
+        })
+    } else if let Some(path) = templated(None) {
+        trace("transcript named by the config's transcript_template");
+        path
+    } else {
+        trace("transcript keyed on the parent shell's pid");
+        temp_dir.join(format!(
+            "{}{}{}",
+            TRANSCRIPT_NAME,
+            process::parent_id(),
+            transcript_format.extension()
+        ))
+    };
+    trace(&format!("transcript path: {}", transcript_path.display()));
+
+    // Only paths that reach the API need a model; -c, -l, -o, history and exports never ask
+    let has_prompt = matches.get_one::<String>("input").is_some()
+        || matches.get_flag("clip")
+        || matches.contains_id("context-from-command");
+    let local_only = ["pin-last", "dump-config", "ensure-conversation"]
+        .iter()
+        .any(|flag| matches.get_flag(flag))
+        || ["export-jsonl", "export-html", "send-request"]
+            .iter()
+            .any(|id| matches.contains_id(id));
+    let sends_request = matches.get_flag("recursive")
+        || matches.contains_id("batch")
+        || (!local_only
+            && (has_prompt || matches.get_flag("regenerate") || matches.contains_id("watch")));
+
+    // First interactive run without any config: offer the model list instead of a guess
+    let first_run = !config_path().is_some_and(|p| p.exists())
+        && !matches.contains_id("model")
+        && !transcript_path.exists()
+        && sends_request
+        && !offline
+        && options.interactive;
+    if matches.get_flag("pick-model") || first_run {
+        trace(if first_run {
+            "first interactive run without config: offering the model picker"
+        } else {
+            "--pick-model given: offering the model picker"
+        });
+        if let Some(picked) = pick_model(&Client::new(client_config.clone()), &model) {
+            model = picked;
+        }
+        if matches.get_flag("pick-model") {
+            return;
+        }
+    }
+    let model = model.as_str();
+
+    if let Some(path) = matches.get_one::<PathBuf>("batch") {
+        trace("--batch given: running independent prompts, transcript untouched");
+        let mut batch_config = client_config.clone();
+        batch_config.model = model.to_string();
+        if let Some(base_url) = config.route_for(model) {
+            batch_config.base_url = base_url.trim_end_matches('/').to_string();
+        }
+        run_batch(
+            path,
+            &Client::new(batch_config),
+            *matches.get_one::<u64>("batch-concurrency").unwrap() as usize,
+            matches.get_one::<PathBuf>("batch-output-dir"),
+        );
+        return;
+    }
+
+    let mut conversation_state = if transcript_path.exists() {
+        trace("transcript exists: resuming it with its own model");
+        ConversationState::load(&transcript_path).unwrap_or_else(|e| {
+            fail(
+                &format!("Unable to load transcript {}", transcript_path.display()),
+                Some(&e),
+            )
+        })
+    } else {
+        let mut fresh = if let Some(persona) = &persona {
+            trace("new conversation seeded from the persona");
+            let mut seeded = ConversationState::with_seed(model, &persona.prompt);
+            seeded.persona = matches.get_one::<String>("persona").cloned();
+            seeded
+        } else if matches.get_flag("minimal-seed") && is_reasoning_model(model) {
+            trace("new conversation with the minimal seed");
+            ConversationState::with_seed(model, MINIMAL_SEED_PROMPT)
+        } else {
+            trace("new conversation with the default seed");
+            ConversationState::new(model)
+        };
+        fresh.temperature = Some(client_config.temperature);
+        fresh.max_tokens = Some(client_config.max_tokens);
+        fresh
+    };
+
+    if matches.get_flag("ensure-conversation") {
+        if transcript_path.exists() {
+            trace("--ensure-conversation given: transcript exists, leaving it alone");
+        } else {
+            trace("--ensure-conversation given: writing the seeded transcript");
+            conversation_state
+                .save(&transcript_path)
+                .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+        }
+        return;
+    }
+
+    if persona.is_some()
+        && conversation_state.persona.as_ref() != matches.get_one::<String>("persona")
+    {
+        eprintln!(
+            "Warning: --persona only seeds new conversations; this one continues as it started."
+        );
+    }
+
+    // Continue with the sampling the conversation started with unless a flag says otherwise
+    if !matches.contains_id("temperature") {
+        if let Some(temperature) = conversation_state.temperature {
+            client_config.temperature = temperature;
+        }
+    }
+    if let Some(max_tokens) = conversation_state.max_tokens {
+        client_config.max_tokens = max_tokens;
+    }
+
+    if let Some(new_model) = matches.get_one::<String>("switch-model") {
+        if *new_model != conversation_state.model {
+            trace("--switch-model given: changing the conversation's model");
+            switch_model(
+                &mut conversation_state,
+                new_model,
+                matches.get_flag("reset-on-model-change"),
+            );
+            conversation_state
+                .save(&transcript_path)
+                .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+        }
+    }
+
+    // A resumed conversation keeps the model it was started with
+    client_config.model = conversation_state.model.clone();
+    (options.strip_thinking_shown, options.strip_thinking_stored) = match matches
+        .get_one::<String>("strip-thinking-tags")
+        .map(|s| s.as_str())
+    {
+        Some("off") => (false, false),
+        Some("transcript") => (true, true),
+        Some(_) => (true, false),
+        None => (emits_thinking_tags(&client_config.model), false),
+    };
+    if let Some(base_url) = config.route_for(&client_config.model) {
+        client_config.base_url = base_url.trim_end_matches('/').to_string();
+    }
+    trace(&format!(
+        "model {} at {}",
+        client_config.model, client_config.base_url
+    ));
+
+    if matches.get_flag("server-state") {
+        // Only OpenAI keeps conversation state; elsewhere the full history is sent as usual
+        client_config.responses_api = client_config.base_url == DEFAULT_BASE_URL;
+        trace(if client_config.responses_api {
+            "--server-state: Responses API, chaining on previous_response_id"
+        } else {
+            "--server-state: provider has no Responses API, sending full history"
+        });
+    }
+
+    if let Some(path) = matches.get_one::<PathBuf>("predict") {
+        if supports_prediction(&client_config.model) {
+            client_config.prediction =
+                Some(fs::read_to_string(path).unwrap_or_else(|e| {
+                    fail(&format!("Unable to read {}", path.display()), Some(&e))
+                }));
+        } else {
+            eprintln!(
+                "Warning: {} does not support predicted outputs, ignoring --predict.",
+                client_config.model
+            );
+        }
+    }
+    // Compiled up front so a broken schema fails before anything is sent
+    let json_schema = matches.get_one::<PathBuf>("json-schema").map(|path| {
+        let schema: Value = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                fail(
+                    &format!("Unable to read JSON schema {}: {}", path.display(), e),
+                    None,
+                )
+            });
+        let validator = JSONSchema::compile(&schema).unwrap_or_else(|e| {
+            fail(
+                &format!("Invalid JSON schema {}: {}", path.display(), e),
+                None,
+            )
+        });
+        if supports_json_schema(&client_config.model) {
+            client_config.json_schema = Some(schema);
+        } else {
+            eprintln!(
+                "Warning: {} does not support structured outputs; the reply is only checked locally.",
+                client_config.model
+            );
+        }
+        validator
+    });
+
+    client_config.save_request = matches.get_one::<PathBuf>("save-request").cloned();
+
+    if matches.get_flag("dump-config") {
+        trace("--dump-config given: printing settings and exiting");
+        dump_config(&client_config, &config, &options, &transcript_path);
+        return;
+    }
+
+    let client = Client::new(client_config);
+
+    if let Some(path) = matches.get_one::<PathBuf>("send-request") {
+        trace("dispatch: --send-request, bypassing the conversation");
+        let saved: Value = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                fail(
+                    &format!("Unable to read saved request {}: {}", path.display(), e),
+                    None,
+                )
+            });
+        match client.replay_request(&saved) {
+            Ok(response) => println!("{}", serde_json::to_string_pretty(&response).unwrap()),
+            Err(e) => fail("Request failed", Some(&e)),
+        }
+        return;
+    }
+
+    let args_text = matches
+        .get_many::<String>("input")
+        .map(|values| values.map(|s| s.as_str()).collect::<Vec<&str>>().join(" "))
+        .filter(|text| !text.trim().is_empty());
+
+    // Determine if input is being piped and get full input
+    let input = if matches.get_flag("clip") {
+        let selection = matches.get_one::<String>("selection").unwrap();
+        let text = read_clipboard_text(selection);
+        Value::String(match &args_text {
+            Some(framing) => format!("{}\n\n{}", text, framing),
+            None => text,
+        })
+    } else if !atty::is(Stream::Stdin) {
+        // Read from stdin
+        let mut buffer = String::new();
+        io::stdin()
+            .read_to_string(&mut buffer)
+            .unwrap_or_else(|e| fail("Failed to read from stdin", Some(&e)));
+        if buffer.trim().is_empty() {
+            Value::Null
+        } else {
+            Value::String(buffer)
+        }
+    } else if let Some(text) = &args_text {
+        Value::String(text.clone())
+    } else {
+        Value::Null
+    };
+    let mut input = input;
+    // Framing for this invocation only; unlike --suffix it is part of the saved turn
+    let prefix = matches.get_one::<String>("input-prefix");
+    let suffix = matches.get_one::<String>("input-suffix");
+    if input.is_null() && (prefix.is_some() || suffix.is_some()) {
+        // The framing alone is a prompt, e.g. for an image from -i
+        let framing: Vec<&str> = prefix
+            .into_iter()
+            .chain(suffix)
+            .map(|s| s.as_str())
+            .collect();
+        input = Value::String(framing.join(" "));
+    } else {
+        if let Some(prefix) = prefix {
+            prepend_text(&mut input, &format!("{} ", prefix));
+        }
+        if let Some(suffix) = suffix {
+            append_text(&mut input, &format!(" {}", suffix));
+        }
+    }
+    let input_string = input.to_string();
+    let has_args = matches.get_one::<String>("input").is_some();
+    trace(if matches.get_flag("clip") {
+        "input taken from the clipboard, arguments appended"
+    } else if !atty::is(Stream::Stdin) && has_args {
+        "input read from piped stdin; arguments are ignored"
+    } else if !atty::is(Stream::Stdin) {
+        "input read from piped stdin"
+    } else if has_args {
+        "input taken from arguments"
+    } else {
+        "no input"
+    });
+    for flag in ["manage", "clear", "last"] {
+        if matches.get_flag(flag) && has_args {
+            trace(&format!("-{} ignored because a prompt was given", flag));
+        }
+    }
+
+    if matches.get_flag("recursive") {
+        trace("dispatch: -r agent mode (takes precedence over every other mode)");
+        options.min_interval = matches.get_one::<f64>("min-interval").map(|&secs| {
+            Duration::try_from_secs_f64(secs).unwrap_or_else(|_| {
+                fail(
+                    "--min-interval must be a non-negative number of seconds.",
+                    None,
+                )
+            })
+        });
+        let mut agent_config = client.config().clone();
+        // Models without JSON mode keep the text protocol
+        agent_options.json_protocol &= supports_json_mode(&agent_config.model);
+        if agent_options.json_protocol {
+            agent_config.json_mode = true;
+        } else {
+            // Cut client-side: an API stop on "\nCOMMAND:" would also fire on the
+            // first command whenever the model writes a preamble line before it
+            options.cut_second_command = true;
+        }
+        handle_recursive_mode(
+            &mut conversation_state,
+            &transcript_path,
+            input_string,
+            &Client::new(agent_config),
+            &options,
+            &agent_options,
+        );
+        return;
+    } else if matches.get_flag("manage") && matches.get_one::<String>("input").is_none() {
+        trace("dispatch: -o manage mode");
+        if !options.interactive {
+            fail("Managing conversations needs a terminal.", None);
+        }
+        manage_ongoing_convos(
+            &mut conversation_state,
+            &transcript_path,
+            *matches.get_one::<usize>("max-conversations").unwrap(),
+            client.config().strict_roles,
+        );
+        return;
+    } else if matches.get_flag("clear") && matches.get_one::<String>("input").is_none() {
+        trace("dispatch: -c clear");
+        clear_current_convo(
+            &conversation_state,
+            &transcript_path,
+            matches.get_flag("yes"),
+            options.interactive,
+        );
+        return;
+    } else if matches.get_flag("last") && matches.get_one::<String>("input").is_none() {
+        trace("dispatch: -l last message");
+        show_last_message(&conversation_state);
+        return;
+    } else if matches.get_flag("pin-last") {
+        trace("dispatch: --pin-last");
+        pin_last_message(&mut conversation_state, &transcript_path);
+        return;
+    } else if let Some(path) = matches.get_one::<PathBuf>("export-jsonl") {
+        trace("dispatch: --export-jsonl");
+        require_assistant_reply(&conversation_state);
+        export_jsonl(&conversation_state, path);
+        return;
+    } else if let Some(path) = matches.get_one::<PathBuf>("export-html") {
+        trace("dispatch: --export-html");
+        require_assistant_reply(&conversation_state);
+        match fs::write(
+            path,
+            render_history_html(&conversation_state, matches.get_flag("with-costs")),
+        ) {
+            Ok(_) => println!("Conversation exported to {}", path.display()),
+            Err(e) => fail("Unable to export conversation", Some(&e)),
+        }
+        return;
+    } else if matches.get_flag("regenerate") {
+        trace("dispatch: --regenerate");
+        regenerate_last_turn(
+            &mut conversation_state,
+            &transcript_path,
+            &client,
+            &options,
+            *matches.get_one::<usize>("samples").unwrap(),
+        );
+        return;
+    }
+
+    // Handle image mode
+    let selection = matches.get_one::<String>("selection").unwrap();
+    if matches.get_flag("image") {
+        // An explicit command is used verbatim and skips detection entirely
+        let clipboard_command = match matches.get_one::<String>("clipboard-cmd") {
+            Some(command) => command.clone(),
+            None => detect_clipboard_command(selection),
+        };
+        trace(&format!(
+            "-i given: attaching clipboard image via `{}`",
+            clipboard_command
+        ));
+        add_image_to_pipeline(&mut input, &clipboard_command);
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("upload-file") {
+        trace("--upload-file given: attaching the uploaded file");
+        add_file_to_pipeline(&mut input, path, &client, &mut conversation_state);
+    }
+    if let Some(command) = matches.get_one::<String>("context-from-command") {
+        trace(&format!(
+            "--context-from-command given: running `{}`",
+            command
+        ));
+        let block = run_context_command(
+            command,
+            Duration::from_secs(*matches.get_one::<u64>("context-timeout").unwrap()),
+        );
+        if input.is_null() {
+            input = Value::String(block);
+        } else {
+            append_text(&mut input, &format!("\n\n{}", block));
+        }
+    }
+
+    if let Some(path) = matches.get_one::<PathBuf>("watch") {
+        trace("dispatch: --watch, re-asking on every change until interrupted");
+        watch_file(
+            path,
+            &input,
+            &mut conversation_state,
+            &transcript_path,
+            &client,
+            &options,
+            matches.get_flag("watch-append"),
+        );
+        return;
+    }
+
+    if input.is_null() {
+        // Piped-but-empty stdin means automation; opening an editor would just hang it
+        if matches.get_flag("stdin-only") || !options.interactive {
+            trace("dispatch: no input and not interactive, refusing to open history");
+            fail(
+                "No input provided. Pass a prompt as arguments or on stdin.",
+                None,
+            );
+        }
+        trace("dispatch: no input, showing history");
+        let grep = matches.get_one::<String>("history-grep").map(|pattern| {
+            let pattern = if matches.get_flag("history-regex") {
+                pattern.clone()
+            } else {
+                regex::escape(pattern)
+            };
+            RegexBuilder::new(&pattern)
+                .case_insensitive(true)
+                .build()
+                .unwrap_or_else(|e| fail("Invalid --history-grep pattern", Some(&e)))
+        });
+        show_history(
+            &conversation_state,
+            matches.get_one::<String>("history-format").unwrap(),
+            matches.get_flag("with-costs"),
+            grep.as_ref(),
+        );
+        return;
+    }
+
+    if let Some(&index) = matches.get_one::<usize>("from-message") {
+        let count = conversation_state.messages.len();
+        if index >= count {
+            fail(
+                &format!(
+                    "--from-message {} is out of range: the conversation has messages 0 to {}.",
+                    index,
+                    count - 1
+                ),
+                None,
+            );
+        }
+        if matches.get_flag("truncate") {
+            trace("--truncate given: dropping later unpinned messages from the transcript");
+            drop_unpinned_after(&mut conversation_state.messages, index);
+        } else {
+            trace("--from-message given: later messages are kept but not sent");
+            options.from_message = Some(index);
+        }
+    }
+
+    if options.confirm_each && !confirm_send(&conversation_state, &input, &options) {
+        println!("Request cancelled.");
+        return;
+    }
+
+    // Default case: simple request
+    trace("dispatch: sending the prompt");
+    perform_request(
+        input,
+        &mut conversation_state,
+        &transcript_path,
+        &client,
+        &options,
+    );
+
+    if let Some(validator) = &json_schema {
+        if let Some(reply) = conversation_state
+            .messages
+            .last()
+            .filter(|m| m.role == "assistant")
+        {
+            check_json_reply(validator, reply.text());
+        }
+    }
+
+    if matches.get_flag("title-auto") && conversation_state.title.is_none() {
+        let mut title_config = client.config().clone();
+        title_config.model = config
+            .title_model
+            .clone()
+            .unwrap_or_else(|| TITLE_MODEL.to_string());
+        title_config.prediction = None;
+        title_config.stop.clear();
+        auto_title(
+            &mut conversation_state,
+            &transcript_path,
+            &Client::new(title_config),
+        );
+    }
+
+    if matches.get_flag("copy") {
+        copy_to_clipboard(
+            &strip_markdown(require_assistant_reply(&conversation_state).text()),
+            &detect_clipboard_command(selection),
+            selection,
+        );
+    }
+}
+
+fn check_json_reply(validator: &JSONSchema, text: &str) {
+    let reply: Value =
+        serde_json::from_str(text).unwrap_or_else(|e| fail("Reply is not valid JSON", Some(&e)));
+    let problems: Vec<String> = match validator.validate(&reply) {
+        Ok(()) => return,
+        Err(errors) => errors
+            .map(|error| format!("  {}: {}", error.instance_path, error))
+            .collect(),
+    };
+    fail(
+        &format!(
+            "Reply does not match the JSON schema:\n{}",
+            problems.join("\n")
+        ),
+        None,
+    );
+}
+
+// Side-request for a short title; the exchange itself is never added to the conversation
+fn auto_title(conversation_state: &mut ConversationState, transcript_path: &Path, client: &Client) {
+    if conversation_state
+        .messages
+        .last()
+        .is_none_or(|m| m.role != "assistant")
+    {
+        return;
+    }
+
+    let prompt = format!(
+        "Give a 3 to 5 word title for the conversation below. Reply with the title only.\n\n{}",
+        flatten_messages(&conversation_state.messages[1..])
+            .trim_end_matches("assistant:")
+            .trim_end()
+    );
+    match client.send(&[Message::new("user", &prompt)]) {
+        Ok(reply) => {
+            let title = reply.text().trim().trim_matches('"').trim();
+            if !title.is_empty() {
+                conversation_state.title = Some(title.to_string());
+                conversation_state
+                    .save(transcript_path)
+                    .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+            }
+        }
+        Err(e) => report_error("Unable to title the conversation", Some(&e)),
+    }
+}
+
+// Old turns are kept unless asked otherwise; the seed follows the new model's role rules
+fn switch_model(conversation_state: &mut ConversationState, model: &str, reset: bool) {
+    if reset {
+        let seed = conversation_state
+            .messages
+            .first()
+            .map(|m| m.text().to_string())
+            .unwrap_or_else(|| ask_rs::SEED_PROMPT.to_string());
+        *conversation_state = ConversationState {
+            temperature: conversation_state.temperature,
+            max_tokens: conversation_state.max_tokens,
+            ..ConversationState::with_seed(model, &seed)
+        };
+    } else {
+        conversation_state.model = model.to_string();
+        if let Some(seed) = conversation_state.messages.first_mut() {
+            seed.role = seed_role(model).to_string();
+        }
+    }
+    eprintln!("Conversation switched to {}.", model);
+}
+
+// Most recently modified transcript whose conversation name starts with `prefix`
+fn latest_transcript_with_prefix(dir: &Path, prefix: &str) -> Option<PathBuf> {
+    let name_prefix = format!("{}{}", TRANSCRIPT_NAME, prefix);
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(&name_prefix))
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .max()
+        .map(|(_, path)| path)
+}
+
+// Independent prompts share nothing but the client; results print in input order
+fn run_batch(path: &Path, client: &Client, concurrency: usize, output_dir: Option<&PathBuf>) {
+    let data = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(&format!("Unable to read {}", path.display()), Some(&e)));
+    let prompts: Vec<(usize, &str)> = data
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+
+    if let Some(dir) = output_dir {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|e| fail("Unable to create batch output directory", Some(&e)));
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<Message, ask_rs::Error>>>> =
+        Mutex::new((0..prompts.len()).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.min(prompts.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some((_, prompt)) = prompts.get(index) else {
+                    break;
+                };
+
+                let mut conversation = ConversationState::new(&client.config().model);
+                conversation.messages.push(Message::new("user", prompt));
+                let result = client.send(&conversation.messages);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    let mut failures = 0;
+    for ((line, _), result) in prompts.iter().zip(results.into_inner().unwrap()) {
+        match result {
+            Some(Ok(reply)) => match output_dir {
+                Some(dir) => {
+                    let out = dir.join(format!("{}.txt", line));
+                    fs::write(&out, reply.text())
+                        .unwrap_or_else(|e| fail("Unable to write batch output", Some(&e)));
+                }
+                None => {
+                    println!("===== line {} =====", line);
+                    println!("{}", reply.text());
+                }
+            },
+            Some(Err(e)) => {
+                failures += 1;
+                report_error(&format!("line {}", line), Some(&e));
+            }
+            None => {}
+        }
+    }
+
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+// Without --watch-append each run replaces the previous one, so the transcript holds the latest answer
+fn watch_file(
+    path: &Path,
+    input: &Value,
+    conversation_state: &mut ConversationState,
+    transcript_path: &Path,
+    client: &Client,
+    options: &RequestOptions,
+    append: bool,
+) {
+    let modified = || fs::metadata(path).and_then(|m| m.modified()).ok();
+    let base = conversation_state.clone();
+    let mut seen = None;
+
+    eprintln!("Watching {}, press Ctrl-C to stop.", path.display());
+    loop {
+        let current = modified();
+        if current.is_none() || current == seen {
+            thread::sleep(WATCH_POLL);
+            continue;
+        }
+
+        // Rapid saves keep pushing the run back until the file settles
+        let mut settled = current;
+        loop {
+            thread::sleep(WATCH_DEBOUNCE);
+            let now = modified();
+            if now == settled {
+                break;
+            }
+            settled = now;
+        }
+        seen = settled;
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                report_error(&format!("Unable to read {}", path.display()), Some(&e));
+                continue;
+            }
+        };
+        let mut prompt = input.clone();
+        let block = format!("{}:\n```\n{}\n```", path.display(), contents.trim_end());
+        if prompt.is_null() {
+            prompt = Value::String(block);
+        } else {
+            append_text(&mut prompt, &format!("\n\n{}", block));
+        }
+
+        if !append {
+            *conversation_state = base.clone();
+        }
+        println!("{}", horizontal_line('='));
+        if options.confirm_each && !confirm_send(conversation_state, &prompt, options) {
+            println!("Request cancelled.");
+            continue;
+        }
+        perform_request(prompt, conversation_state, transcript_path, client, options);
+        if options.budget_exhausted() {
+            return;
+        }
+    }
+}
+
+// Last look at what is about to leave the machine, after every per-request addition
+fn confirm_send(
+    conversation_state: &ConversationState,
+    input: &Value,
+    options: &RequestOptions,
+) -> bool {
+    let mut pending = conversation_state.clone();
+    pending.messages.push(Message {
+        role: "user".to_string(),
+        content: input.clone(),
+        ..Default::default()
+    });
+    let outgoing = outgoing_messages(&pending, options);
+    let text = outgoing.last().map(|m| m.text()).unwrap_or_default();
+    let preview: String = text.chars().take(CONFIRM_PREVIEW_CHARS).collect();
+
+    // Only a system turn is separate from the prompt; on reasoning models it is folded in
+    if let Some(instruction) = &options.prepend_system {
+        if !is_reasoning_model(&conversation_state.model) {
+            println!("[system] {}", instruction);
+        }
+    }
+    println!("{}", preview);
+    if text.chars().count() > CONFIRM_PREVIEW_CHARS {
+        println!("... ({} characters total)", text.chars().count());
+    }
+    if let Some(parts) = input.as_array() {
+        println!("+ {} attachment(s)", parts.len().saturating_sub(1));
+    }
+
+    dialoguer::Confirm::new()
+        .with_prompt("Send this prompt?")
+        .default(false)
+        .interact()
+        .unwrap_or(false)
+}
+
+// X11 has separate PRIMARY and CLIPBOARD selections; Wayland only has one
+fn detect_clipboard_command(selection: &str) -> String {
+    let output = ProcessCommand::new("ps")
+        .arg("-A")
+        .output()
+        .unwrap_or_else(|e| fail("Failed to execute ps command", Some(&e)));
+    let os_out = String::from_utf8_lossy(&output.stdout);
+
+    if os_out.to_lowercase().contains("xorg") {
+        CLIPBOARD_COMMAND_XORG.replace("{selection}", selection)
+    } else if os_out.to_lowercase().contains("wayland") {
+        CLIPBOARD_COMMAND_WAYLAND.to_string()
+    } else {
+        CLIPBOARD_COMMAND_UNSUPPORTED.to_string()
+    }
+}
+
+// Writes to the same clipboard the paste command reads from
+fn copy_to_clipboard(text: &str, clipboard_command: &str, selection: &str) {
+    let copy_command = if clipboard_command == CLIPBOARD_COMMAND_WAYLAND {
+        CLIPBOARD_COPY_WAYLAND.to_string()
+    } else if clipboard_command == CLIPBOARD_COMMAND_UNSUPPORTED {
+        eprintln!("Unsupported OS/DE combination, reply was not copied.");
+        return;
+    } else {
+        CLIPBOARD_COPY_XORG.replace("{selection}", selection)
+    };
+
+    let result = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(&copy_command)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            child.stdin.take().unwrap().write_all(text.as_bytes())?;
+            child.wait()
+        });
+
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Clipboard command `{}` failed ({})", copy_command, status),
+        Err(e) => report_error("Unable to copy reply to the clipboard", Some(&e)),
+    }
+}
+
+fn add_image_to_pipeline(input: &mut Value, clipboard_command: &str) {
+    if clipboard_command == CLIPBOARD_COMMAND_UNSUPPORTED {
+        fail(
+            "Unsupported OS/DE combination. Only Xorg and Wayland are supported.",
+            None,
+        );
+    }
+
+    let output = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(clipboard_command)
+        .output()
+        .unwrap_or_else(|e| fail("Failed to execute clipboard command", Some(&e)));
+
+    // An empty clipboard or missing display would otherwise be sent as a broken image
+    if !output.status.success() {
+        fail(
+            &format!(
+                "Clipboard command `{}` failed ({}): {}",
+                clipboard_command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            None,
+        );
+    }
+
+    let image_buffer = base64::engine::general_purpose::STANDARD.encode(&output.stdout);
+
+    append_content_part(
+        input,
+        serde_json::json!({
+            "type": "image_url",
+            "image_url": {
+                "url": format!("data:image/png;base64,{}", image_buffer),
+                "detail": VISION_DETAIL,
+            }
+        }),
+    );
+}
+
+fn read_clipboard_text(selection: &str) -> String {
+    let command = if cfg!(target_os = "macos") {
+        CLIPBOARD_TEXT_MACOS.to_string()
+    } else {
+        match detect_clipboard_command(selection).as_str() {
+            CLIPBOARD_COMMAND_WAYLAND => CLIPBOARD_TEXT_WAYLAND.to_string(),
+            CLIPBOARD_COMMAND_UNSUPPORTED => fail(
+                "Unsupported OS/DE combination. Only Xorg, Wayland and macOS are supported.",
+                None,
+            ),
+            _ => CLIPBOARD_TEXT_XORG.replace("{selection}", selection),
+        }
+    };
+
+    let output = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .output()
+        .unwrap_or_else(|e| fail("Failed to execute clipboard command", Some(&e)));
+    if !output.status.success() {
+        fail(
+            &format!(
+                "Clipboard command `{}` failed ({}): {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            None,
+        );
+    }
+
+    match String::from_utf8(output.stdout) {
+        Ok(text) if !text.trim().is_empty() => text,
+        Ok(_) => fail("The clipboard is empty.", None),
+        Err(_) => fail("The clipboard doesn't hold text; use -i for images.", None),
+    }
+}
+
+// Trusted and run once, unlike agent commands; a hung command is killed after the timeout
+fn run_context_command(command: &str, timeout: Duration) -> String {
+    let mut child = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| fail(&format!("Unable to run `{}`", command), Some(&e)));
+
+    // Drained on their own threads so a chatty command can't fill a pipe and stall
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut text = Vec::new();
+            let _ = pipe.read_to_end(&mut text);
+            String::from_utf8_lossy(&text).into_owned()
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().unwrap()));
+    let stderr = read(Box::new(child.stderr.take().unwrap()));
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < timeout => thread::sleep(CONTEXT_POLL),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                fail(
+                    &format!(
+                        "`{}` did not finish within {}s, stopping.",
+                        command,
+                        timeout.as_secs()
+                    ),
+                    None,
+                );
+            }
+        }
+    };
+    let (stdout, stderr) = (stdout.join().unwrap(), stderr.join().unwrap());
+
+    let mut block = format!("Output of `{}`:\n```\n{}\n```", command, stdout.trim_end());
+    if !stderr.trim().is_empty() {
+        block.push_str(&format!("\nstderr:\n```\n{}\n```", stderr.trim_end()));
+    }
+    if !status.success() {
+        block.push_str(&format!("\n({})", status));
+    }
+    block
+}
+
+// Upload the file and reference it from the current turn
+fn add_file_to_pipeline(
+    input: &mut Value,
+    path: &Path,
+    client: &Client,
+    conversation_state: &mut ConversationState,
+) {
+    let file_id = client
+        .upload_file(path)
+        .unwrap_or_else(|e| fail(&format!("Failed to upload {}", path.display()), Some(&e)));
+
+    append_content_part(
+        input,
+        serde_json::json!({
+            "type": "file",
+            "file": { "file_id": file_id },
+        }),
+    );
+    conversation_state.files.push(file_id);
+}
+
+fn perform_request(
+    input: Value,
+    conversation_state: &mut ConversationState,
+    transcript_path: &Path,
+    client: &Client,
+    options: &RequestOptions,
+) {
+    if options.budget_exhausted() {
+        return;
+    }
+
+    conversation_state.messages.push(Message {
+        role: "user".to_string(),
+        content: input,
+        timestamp: Some(unix_now()),
+        ..Default::default()
+    });
+
+    let messages = outgoing_messages(conversation_state, options);
+    if options.show_trim {
+        show_trim(conversation_state, &messages, options);
+    }
+    warn_context_window(
+        &conversation_state.model,
+        &messages,
+        options.context_warn_percent,
+    );
+    options.request_time.set(Duration::ZERO);
+    let mut result = options.send(client, &messages);
+
+    // Capped at a single retry so a model that keeps answering blank can't loop us
+    if options.retry_empty
+        && !options.budget_exhausted()
+        && result.as_ref().is_ok_and(|r| is_empty_reply(&r.message))
+    {
+        if options.verbose {
+            eprintln!("Empty reply from the model, retrying once.");
+        }
+        result = options.send(client, &messages);
+    }
+
+    match result {
+        Ok(reply) => {
+            report_reply(&reply, options);
+            process_response(reply.message, conversation_state, transcript_path, options);
+        }
+        Err(ask_rs::Error::Offline) => fail(
+            "Not sending: network access is disabled by --no-network or ASK_OFFLINE.",
+            None,
+        ),
+        Err(e) => report_error("Request failed", Some(&e)),
+    }
+}
+
+// Timing, request id and quota for a reply that came back through RequestOptions::send
+fn report_reply(reply: &Reply, options: &RequestOptions) {
+    if options.timing {
+        eprintln!(
+            "Request took {:.3}s",
+            options.request_time.get().as_secs_f64()
+        );
+    }
+    if options.show_request_id || options.verbose {
+        if let Some(request_id) = &reply.request_id {
+            eprintln!("Request id: {}", request_id);
+        }
+    }
+    report_rate_limit(&reply.rate_limit, options.verbose);
+}
+
+// Re-ask the last user turn; with several samples the transcript only gets the one picked
+fn regenerate_last_turn(
+    conversation_state: &mut ConversationState,
+    transcript_path: &Path,
+    client: &Client,
+    options: &RequestOptions,
+    samples: usize,
+) {
+    let mut replay = conversation_state.clone();
+    if replay
+        .messages
+        .last()
+        .is_some_and(|m| m.role == "assistant")
+    {
+        replay.messages.pop();
+    }
+    if replay.messages.len() < 2 || replay.messages.last().unwrap().role != "user" {
+        fail(
+            "Nothing to regenerate: the conversation has no user turn yet.",
+            None,
+        );
+    }
+
+    let messages = outgoing_messages(&replay, options);
+
+    if samples <= 1 {
+        options.request_time.set(Duration::ZERO);
+        match options.send(client, &messages) {
+            Ok(reply) => {
+                report_reply(&reply, options);
+                process_response(reply.message, &mut replay, transcript_path, options);
+            }
+            Err(e) => fail("Request failed", Some(&e)),
+        }
+        *conversation_state = replay;
+        return;
+    }
+
+    // Numbered as printed, so a failed sample leaves a gap rather than shifting the rest
+    let mut replies = Vec::new();
+    for sample in 1..=samples {
+        if options.budget_exhausted() {
+            break;
+        }
+        options.request_time.set(Duration::ZERO);
+        match options.send(client, &messages) {
+            Ok(reply) => {
+                report_reply(&reply, options);
+                let reply = reply.message;
+                println!("===== sample {} =====", sample);
+                display_reply(shown_text(&reply, options), options);
+                replies.push((sample, reply));
+            }
+            Err(e) => report_error(&format!("sample {}", sample), Some(&e)),
+        }
+    }
+
+    if replies.is_empty() || !options.interactive {
+        return;
+    }
+
+    let mut items: Vec<String> = replies
+        .iter()
+        .map(|(sample, _)| format!("Keep sample {}", sample))
+        .collect();
+    items.push("Keep the original reply".to_string());
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Which reply should stay in the conversation?")
+        .default(0)
+        .items(&items)
+        .interact();
+
+    if let Some(index) = selection.ok().filter(|&index| index < replies.len()) {
+        let (_, mut kept) = replies.swap_remove(index);
+        strip_stored_thinking(&mut kept, options);
+        kept.model.get_or_insert_with(|| replay.model.clone());
+        kept.timestamp.get_or_insert_with(unix_now);
+        replay.messages.push(kept);
+        replay
+            .save(transcript_path)
+            .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+        *conversation_state = replay;
+    }
+}
+
+// Per-request adjustments that are sent but never written to the transcript
+fn outgoing_messages(
+    conversation_state: &ConversationState,
+    options: &RequestOptions,
+) -> Vec<Message> {
+    let mut messages = conversation_state.messages.clone();
+
+    // The new turn is always last; everything after the chosen index but pinned turns is left out
+    if let Some(index) = options.from_message {
+        let new_turn = messages.pop();
+        drop_unpinned_after(&mut messages, index);
+        messages.extend(new_turn);
+        // The server's copy still has the dropped turns, so don't chain onto it
+        for message in &mut messages {
+            message.response_id = None;
+        }
+    }
+
+    if let Some(suffix) = &options.prompt_suffix {
+        if let Some(last) = messages.last_mut().filter(|m| m.role == "user") {
+            append_text(&mut last.content, &format!("\n\n{}", suffix));
+        }
+    }
+
+    if options.explain {
+        if let Some(last) = messages.last_mut().filter(|m| m.role == "user") {
+            prepend_text(&mut last.content, &format!("{}\n\n", EXPLAIN_INSTRUCTION));
+        }
+    }
+
+    if let Some(instruction) = &options.prepend_system {
+        if is_reasoning_model(&conversation_state.model) {
+            // No system role here, so fold it into the user turn being sent
+            if let Some(last) = messages.last_mut() {
+                prepend_text(&mut last.content, &format!("{}\n\n", instruction));
+            }
+        } else {
+            let position = messages.len().min(1);
+            messages.insert(position, Message::new("system", instruction));
+        }
+    }
+
+    messages
+}
+
+// Pinned messages are never trimmed, so they stay in place
+fn drop_unpinned_after(messages: &mut Vec<Message>, index: usize) {
+    let later = messages.split_off((index + 1).min(messages.len()));
+    messages.extend(later.into_iter().filter(|m| m.pinned));
+}
+
+// Report what outgoing_messages left out; nothing here changes what is sent
+fn show_trim(
+    conversation_state: &ConversationState,
+    messages: &[Message],
+    options: &RequestOptions,
+) {
+    let count = conversation_state.messages.len();
+    // The last message is the new turn, which is always sent
+    let left_out: Vec<String> = match options.from_message {
+        Some(index) => conversation_state.messages[..count.saturating_sub(1)]
+            .iter()
+            .enumerate()
+            .skip(index + 1)
+            .filter(|(_, m)| !m.pinned)
+            .map(|(i, _)| i.to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+    match options.from_message {
+        Some(index) if !left_out.is_empty() => eprintln!(
+            "Trimmed: messages {} are not sent (--from-message {}).",
+            left_out.join(", "),
+            index
+        ),
+        _ => eprintln!("Trimmed: nothing, all {} messages are sent.", count),
+    }
+    eprintln!(
+        "Estimated prompt: {} tokens sent, {} for the whole conversation.",
+        estimate_tokens(messages),
+        estimate_tokens(&conversation_state.messages)
+    );
+}
+
+// Unknown models have no entry in the table and never warn
+fn warn_context_window(model: &str, messages: &[Message], percent: u8) {
+    let Some(window) = context_window(model) else {
+        return;
+    };
+    let estimated = estimate_tokens(messages);
+    if percent > 0 && estimated * 100 >= window as usize * percent as usize {
+        eprintln!(
+            "Warning: about {} of {}'s {} context tokens are used ({}%).",
+            estimated,
+            model,
+            window,
+            estimated * 100 / window as usize
+        );
+    }
+}
+
+fn report_rate_limit(rate_limit: &RateLimit, verbose: bool) {
+    let low = rate_limit.is_low(RATE_LIMIT_WARN_FRACTION);
+    let reported = rate_limit.remaining_requests.is_some() || rate_limit.remaining_tokens.is_some();
+    if !reported || !(verbose || low) {
+        return;
+    }
+
+    let show = |value: Option<u64>| value.map_or("?".to_string(), |v| v.to_string());
+    eprintln!(
+        "{}rate limit: {}/{} requests, {}/{} tokens remaining",
+        if low { "Warning: low " } else { "" },
+        show(rate_limit.remaining_requests),
+        show(rate_limit.limit_requests),
+        show(rate_limit.remaining_tokens),
+        show(rate_limit.limit_tokens)
+    );
+}
+
+// One line by default; --loud-errors adds the cause chain, Debug form and a backtrace.
+// Problems without an underlying error pass None and print the message alone.
+fn report_error(context: &str, error: Option<&dyn std::error::Error>) {
+    let Some(error) = error else {
+        eprintln!("{}", context);
+        return;
+    };
+    eprintln!("{}: {}", context, error);
+    if !LOUD_ERRORS.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut source = error.source();
+    while let Some(cause) = source {
+        eprintln!("  caused by: {}", cause);
+        source = cause.source();
+    }
+    eprintln!("{:?}", error);
+    eprintln!("{}", Backtrace::force_capture());
+}
+
+fn fail(context: &str, error: Option<&dyn std::error::Error>) -> ! {
+    report_error(context, error);
+    std::process::exit(1);
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn is_empty_reply(reply: &Message) -> bool {
+    reply.tool_calls.is_none() && reply.content.as_str().unwrap_or("").trim().is_empty()
+}
+
+fn process_response(
+    mut reply: Message,
+    conversation_state: &mut ConversationState,
+    transcript_path: &Path,
+    options: &RequestOptions,
+) {
+    strip_stored_thinking(&mut reply, options);
+    if options.cut_second_command {
+        if let Some(text) = reply.content.as_str() {
+            reply.content = Value::String(cut_after_first_command(text).to_string());
+        }
+    }
+    let shown = shown_text(&reply, options);
+
+    if reply.model.is_none() {
+        reply.model = Some(conversation_state.model.clone());
+    }
+    reply.timestamp.get_or_insert_with(unix_now);
+    conversation_state.messages.push(reply);
+
+    conversation_state
+        .save(transcript_path)
+        .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+
+    if let Some(path) = &options.fifo {
+        write_to_fifo(path, &shown);
+        if options.fifo_only {
+            return;
+        }
+    }
+
+    display_reply(shown, options);
+}
+
+fn strip_stored_thinking(reply: &mut Message, options: &RequestOptions) {
+    if options.strip_thinking_stored {
+        if let Some(text) = reply.content.as_str() {
+            reply.content = Value::String(strip_thinking_tags(text));
+        }
+    }
+}
+
+fn shown_text(reply: &Message, options: &RequestOptions) -> String {
+    match (&reply.content, &reply.tool_calls) {
+        // Tool-call replies have no text; show what the model asked for instead
+        (Value::Null, Some(tool_calls)) => tool_calls
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|call| {
+                let function = call.get("function");
+                format!(
+                    "Tool call {}: {}({})",
+                    call.get("id").and_then(|v| v.as_str()).unwrap_or("?"),
+                    function
+                        .and_then(|f| f.get("name"))
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("?"),
+                    function
+                        .and_then(|f| f.get("arguments"))
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("")
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        (content, _) if options.strip_thinking_shown => {
+            strip_thinking_tags(content.as_str().unwrap_or(""))
+        }
+        (content, _) => content.as_str().unwrap_or("").to_string(),
+    }
+}
+
+fn display_reply(shown: String, options: &RequestOptions) {
+    // Only the display is cut; the transcript above keeps the whole reply
+    let shown = match options.output_lines {
+        Some(limit) if shown.lines().count() > limit => {
+            let hidden = shown.lines().count() - limit;
+            let mut kept: Vec<&str> = shown.lines().take(limit).collect();
+            let note = format!("({} more lines, see -l)", hidden);
+            kept.push(&note);
+            kept.join("\n")
         }
+        _ => shown,
+    };
+
+    if options.pager && atty::is(Stream::Stdout) {
+        page_text(&shown);
     } else {
-        Value::Null
+        println!("{}", shown);
+    }
+}
+
+// A FIFO nobody is reading would block forever, so it is opened non-blocking and skipped
+fn write_to_fifo(path: &Path, text: &str) {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path);
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+            eprintln!("No reader on {}, reply not written there.", path.display());
+            return;
+        }
+        Err(e) => return report_error(&format!("Unable to open {}", path.display()), Some(&e)),
     };
-    let mut input = input;
-    let input_string = input.to_string();
 
-    if matches.get_flag("recursive") {
-        handle_recursive_mode(&mut conversation_state, &transcript_path, input_string);
-        return;
-    } else if matches.get_flag("manage") && !matches.get_one::<String>("input").is_some() {
-        manage_ongoing_convos(&mut conversation_state, &transcript_path);
-        return;
-    } else if matches.get_flag("clear") && !matches.get_one::<String>("input").is_some() {
-        clear_current_convo(&transcript_path);
-        return;
-    } else if matches.get_flag("last") && !matches.get_one::<String>("input").is_some() {
-        if let Some(last_message) = conversation_state.messages.last() {
-            println!("{}", serde_json::to_string(&last_message.content).unwrap());
+    // Blocking again once a reader is connected, so a full pipe waits instead of failing
+    unsafe {
+        let fd = file.as_raw_fd();
+        libc::fcntl(
+            fd,
+            libc::F_SETFL,
+            libc::fcntl(fd, libc::F_GETFL) & !libc::O_NONBLOCK,
+        );
+    }
+    match writeln!(file, "{}", text) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            eprintln!(
+                "The reader on {} went away before the reply was written.",
+                path.display()
+            );
         }
-        return;
+        Err(e) => report_error(&format!("Unable to write to {}", path.display()), Some(&e)),
     }
+}
 
-    // Handle image mode
-    let clipboard_command = detect_clipboard_command();
-    if matches.get_flag("image") {
-        add_image_to_pipeline(&mut input, &clipboard_command);
-    }
+// $PAGER first, then less and more; printing is the last resort
+fn page_text(text: &str) {
+    let mut candidates: Vec<String> = env::var("PAGER")
+        .ok()
+        .filter(|p| !p.trim().is_empty())
+        .into_iter()
+        .collect();
+    candidates.extend(["less -R".to_string(), "more".to_string()]);
 
-    if input.is_null() {
-        show_history(&conversation_state);
+    for candidate in candidates {
+        let mut parts = candidate.split_whitespace();
+        let Some(program) = parts.next() else {
+            continue;
+        };
+        let Ok(mut child) = ProcessCommand::new(program)
+            .args(parts)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+        else {
+            continue;
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            // Quitting the pager early closes the pipe; that's not an error
+            let _ = writeln!(stdin, "{}", text);
+        }
+        let _ = child.wait();
         return;
     }
 
-    // Default case: simple request
-    perform_request(
-        input,
-        &mut conversation_state,
-        &transcript_path,
-        &clipboard_command,
-    );
+    println!("{}", text);
 }
 
-fn detect_clipboard_command() -> String {
-    let output = ProcessCommand::new("ps")
-        .arg("-A")
-        .output()
-        .expect("Failed to execute ps command");
-    let os_out = String::from_utf8_lossy(&output.stdout);
+fn clear_current_convo(
+    conversation_state: &ConversationState,
+    transcript_path: &Path,
+    assume_yes: bool,
+    interactive: bool,
+) {
+    // Short threads go without asking; long ones are easy to lose by accident
+    if !assume_yes && conversation_state.messages.len() > CLEAR_CONFIRM_MESSAGES {
+        if !interactive {
+            fail(
+                &format!(
+                    "Refusing to clear a conversation with {} messages without confirmation. Pass --yes to clear it anyway.",
+                    conversation_state.messages.len()
+                ),
+                None,
+            );
+        }
 
-    if os_out.to_lowercase().contains("xorg") {
-        CLIPBOARD_COMMAND_XORG.to_string()
-    } else if os_out.to_lowercase().contains("wayland") {
-        CLIPBOARD_COMMAND_WAYLAND.to_string()
-    } else {
-        CLIPBOARD_COMMAND_UNSUPPORTED.to_string()
+        let confirm = dialoguer::Confirm::new()
+            .with_prompt(format!(
+                "Clear the current conversation ({} messages)?",
+                conversation_state.messages.len()
+            ))
+            .default(false)
+            .interact()
+            .unwrap_or(false);
+        if !confirm {
+            println!("Operation cancelled.");
+            return;
+        }
+    }
+
+    match fs::remove_file(transcript_path) {
+        Ok(_) => println!("Conversation cleared."),
+        Err(e) => println!("Error clearing conversation: {}", e),
     }
 }
 
-fn add_image_to_pipeline(input: &mut Value, clipboard_command: &str) {
-    if clipboard_command == CLIPBOARD_COMMAND_UNSUPPORTED {
-        panic!("Unsupported OS/DE combination. Only Xorg and Wayland are supported.");
+// Read-only views take the state by shared reference and never touch the transcript
+fn show_last_message(conversation_state: &ConversationState) {
+    let reply = require_assistant_reply(conversation_state);
+    println!("{}", serde_json::to_string(&reply.content).unwrap());
+}
+
+// Read features never fall back to the seed prompt
+fn require_assistant_reply(conversation_state: &ConversationState) -> &Message {
+    conversation_state
+        .last_assistant_message()
+        .unwrap_or_else(|| fail("No assistant reply yet in this conversation.", None))
+}
+
+fn pin_last_message(conversation_state: &mut ConversationState, transcript_path: &Path) {
+    match conversation_state.messages.last_mut() {
+        // Only the seed exists until the first turn is saved
+        Some(message) if transcript_path.exists() => message.pinned = true,
+        _ => fail("No message to pin.", None),
     }
 
-    let output = ProcessCommand::new("sh")
-        .arg("-c")
-        .arg(clipboard_command)
-        .output()
-        .expect("Failed to execute clipboard command");
+    conversation_state
+        .save(transcript_path)
+        .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+    println!("Last message pinned.");
+}
 
-    let image_buffer = base64::encode(&output.stdout);
+// One `{"messages": [...]}` line per export, keeping only what the fine-tuning format knows
+fn export_jsonl(conversation_state: &ConversationState, path: &Path) {
+    let messages: Vec<Value> = conversation_state
+        .messages
+        .iter()
+        .map(|m| {
+            let mut message = serde_json::json!({ "role": m.role, "content": m.content });
+            if let Some(tool_calls) = &m.tool_calls {
+                message["tool_calls"] = tool_calls.clone();
+            }
+            message
+        })
+        .collect();
+    let line = serde_json::json!({ "messages": messages });
 
-    let user_text = input.as_str().unwrap_or("");
-    let new_input = serde_json::json!([
-        {
-            "type": "text",
-            "text": user_text,
-        },
-        {
-            "type": "image_url",
-            "image_url": {
-                "url": format!("data:image/png;base64,{}", image_buffer),
-                "detail": VISION_DETAIL,
+    let result = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+
+    match result {
+        Ok(_) => println!("Conversation exported to {}", path.display()),
+        Err(e) => fail("Unable to export conversation", Some(&e)),
+    }
+}
+
+fn show_history(
+    conversation_state: &ConversationState,
+    format: &str,
+    with_costs: bool,
+    grep: Option<&Regex>,
+) {
+    let mut filtered;
+    let mut hidden_note = String::new();
+    let conversation_state = match grep {
+        Some(pattern) => {
+            filtered = conversation_state.clone();
+            filtered.messages.retain(|m| pattern.is_match(m.text()));
+            let hidden = conversation_state.messages.len() - filtered.messages.len();
+            if hidden > 0 {
+                hidden_note = format!(
+                    "({} of {} messages hidden by --history-grep)\n",
+                    hidden,
+                    conversation_state.messages.len()
+                );
             }
+            &filtered
         }
-    ]);
+        None => conversation_state,
+    };
+
+    let (content, extension) = match format {
+        "markdown" => (
+            render_history_markdown(conversation_state, with_costs),
+            ".md",
+        ),
+        "json" => (
+            serde_json::to_string_pretty(conversation_state).unwrap(),
+            ".json",
+        ),
+        _ => (render_history_plain(conversation_state), ""),
+    };
+    // JSON stays machine-readable, so it only gets the filtering
+    let content = if format == "json" {
+        content
+    } else {
+        hidden_note + &content
+    };
+
+    // The extension lets editors pick the right highlighting
+    let tmp_dir = env::temp_dir();
+    let tmp_path = tmp_dir.join(format!("ask_hist{}", extension));
 
-    *input = new_input;
+    fs::write(&tmp_path, content)
+        .unwrap_or_else(|e| fail("Unable to write history file", Some(&e)));
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "more".to_string());
+    ProcessCommand::new(editor)
+        .arg(&tmp_path)
+        .status()
+        .unwrap_or_else(|e| fail("Failed to open editor", Some(&e)));
+
+    fs::remove_file(&tmp_path)
+        .unwrap_or_else(|e| fail("Unable to delete temporary history file", Some(&e)));
 }
 
-fn perform_request(
-    input: Value,
-    conversation_state: &mut ConversationState,
-    transcript_path: &PathBuf,
-    _clipboard_command: &str, // Prefixed with underscore to indicate intentional unused variable
-) {
-    conversation_state.messages.push(Message {
-        role: "user".to_string(),
-        content: input,
-    });
+// "assistant (gpt-4o) (pinned) 2024-05-01 14:03 UTC" style banner text; old transcripts lack the extras
+fn message_label(message: &Message) -> String {
+    let mut label = message.role.clone();
+    if let Some(model) = &message.model {
+        label.push_str(&format!(" ({})", model));
+    }
+    if message.pinned {
+        label.push_str(" (pinned)");
+    }
+    if let Some(timestamp) = message.timestamp {
+        label.push_str(&format!(" {}", format_timestamp(timestamp)));
+    }
+    label
+}
 
-    let mut body = serde_json::json!({
-        "messages": conversation_state.messages,
-        "model": conversation_state.model,
-        "user": whoami::username(),
-    });
+// "2024-05-01 14:03 UTC"
+fn format_timestamp(timestamp: u64) -> String {
+    let minutes = timestamp % 86_400 / 60;
+    format!(
+        "{} {:02}:{:02} UTC",
+        format_date(timestamp),
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+// "2024-05-01"; the inverse of Howard Hinnant's days_from_civil
+fn format_date(timestamp: u64) -> String {
+    let z = (timestamp / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn render_history_plain(conversation_state: &ConversationState) -> String {
+    let mut content = String::new();
 
-    if !conversation_state.model.contains("o1-") {
-        body["max_tokens"] = serde_json::json!(MAX_TOKENS);
-        body["temperature"] = serde_json::json!(TEMPERATURE);
+    for message in &conversation_state.messages {
+        content.push_str("\n\n");
+        content.push_str(&horizontal_line('▃'));
+        content.push_str(&format!("▍{} ▐\n", message_label(message)));
+        content.push_str(&horizontal_line('▀'));
+        content.push('\n');
+        content.push_str(message.text());
     }
 
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(&format!("https://{}{}", HOST, ENDPOINT))
-        .header("Authorization", format!("Bearer {}", get_api_key()))
-        .json(&body)
-        .send();
+    content
+}
 
-    match res {
-        Ok(response) => {
-            let data: Value = response.json().unwrap();
-            process_response(&data, conversation_state, transcript_path);
-        }
-        Err(e) => {
-            eprintln!("HTTP request error: {}", e);
+// Footnote with the reply's token usage and, for priced models, its estimated cost
+fn cost_note(message: &Message, conversation_model: &str) -> Option<String> {
+    let usage = message.usage?;
+    let model = message.model.as_deref().unwrap_or(conversation_model);
+    let mut note = format!(
+        "Tokens: {} prompt, {} completion",
+        usage.prompt_tokens, usage.completion_tokens
+    );
+    if let Some(cost) = usage.cost(model) {
+        note.push_str(&format!(", about ${:.4}", cost));
+    }
+    Some(note)
+}
+
+fn render_history_markdown(conversation_state: &ConversationState, with_costs: bool) -> String {
+    let mut content = format!(
+        "# {} ({})\n",
+        conversation_state
+            .title
+            .as_deref()
+            .unwrap_or("Conversation"),
+        conversation_state.model
+    );
+
+    for message in &conversation_state.messages {
+        content.push_str(&format!("\n## {}\n\n", message_label(message)));
+        content.push_str(message.text());
+        content.push('\n');
+        if let Some(note) = cost_note(message, &conversation_state.model).filter(|_| with_costs) {
+            content.push_str(&format!("\n<sub>{}</sub>\n", note));
         }
     }
+
+    content
 }
 
-fn process_response(
-    data: &Value,
-    conversation_state: &mut ConversationState,
-    transcript_path: &PathBuf,
-) {
-    if let Some(choices) = data.get("choices") {
-        if let Some(choice) = choices.get(0) {
-            if let Some(message) = choice.get("message") {
-                let content = message.get("content").unwrap_or(&Value::Null).clone();
-                let role = message
-                    .get("role")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("")
-                    .to_string();
+fn render_history_html(conversation_state: &ConversationState, with_costs: bool) -> String {
+    let syntaxes = SyntaxSet::load_defaults_newlines();
+    let theme = &ThemeSet::load_defaults().themes[HTML_CODE_THEME];
+    let title = conversation_state
+        .title
+        .as_deref()
+        .unwrap_or("Conversation");
 
-                println!("{}", content.as_str().unwrap_or(""));
+    let mut body = String::new();
+    for message in &conversation_state.messages {
+        body.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n",
+            html_escape(&message.role),
+            html_escape(&message_label(message))
+        ));
+
+        let parts = match &message.content {
+            Value::Array(parts) => parts.clone(),
+            content => {
+                vec![serde_json::json!({ "type": "text", "text": content.as_str().unwrap_or("") })]
+            }
+        };
+        for part in parts {
+            if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                body.push_str(&markdown_to_html(text, &syntaxes, theme));
+            } else if let Some(url) = part.pointer("/image_url/url").and_then(|v| v.as_str()) {
+                // Stored images are already data URLs, so the file stays self-contained
+                body.push_str(&format!("<img src=\"{}\">\n", html_escape(url)));
+            }
+        }
+        if let Some(note) = cost_note(message, &conversation_state.model).filter(|_| with_costs) {
+            body.push_str(&format!("<p class=\"cost\">{}</p>\n", html_escape(&note)));
+        }
+        body.push_str("</section>\n");
+    }
 
-                let assistant_message = Message { role, content };
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{} ({})</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{} ({})</h1>\n{}</body>\n</html>\n",
+        html_escape(title),
+        html_escape(&conversation_state.model),
+        HTML_STYLE,
+        html_escape(title),
+        html_escape(&conversation_state.model),
+        body
+    )
+}
 
-                conversation_state.messages.push(assistant_message);
+// Fenced code goes through syntect; everything else is plain pulldown-cmark output
+fn markdown_to_html(text: &str, syntaxes: &SyntaxSet, theme: &Theme) -> String {
+    let mut events = Vec::new();
+    let mut code: Option<(String, String)> = None;
 
-                let conversation_json = serde_json::to_string(&conversation_state).unwrap();
-                fs::write(transcript_path, conversation_json)
-                    .expect("Unable to write transcript file");
+    for event in Parser::new_ext(text, Options::all()) {
+        match (event, &mut code) {
+            (Event::Start(Tag::CodeBlock(kind)), _) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(language) => language.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((language, String::new()));
+            }
+            (Event::Text(chunk), Some((_, source))) => source.push_str(&chunk),
+            // Raw HTML in a reply is shown, not interpreted
+            (Event::Html(raw) | Event::InlineHtml(raw), _) => events.push(Event::Text(raw)),
+            (Event::End(TagEnd::CodeBlock), _) => {
+                let (language, source) = code.take().unwrap_or_default();
+                let syntax = syntaxes
+                    .find_syntax_by_token(&language)
+                    .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+                let highlighted = highlighted_html_for_string(&source, syntaxes, syntax, theme)
+                    .unwrap_or_else(|_| format!("<pre>{}</pre>", html_escape(&source)));
+                events.push(Event::Html(highlighted.into()));
             }
+            (event, _) => events.push(event),
         }
-    } else {
-        eprintln!(
-            "Error processing API return. Full response ahead:\n{}\n",
-            data
-        );
     }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    html
 }
 
-fn clear_current_convo(transcript_path: &PathBuf) {
-    match fs::remove_file(transcript_path) {
-        Ok(_) => println!("Conversation cleared."),
-        Err(e) => println!("Error clearing conversation: {}", e),
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Accept either a path or a bare conversation name from the temp dir
+fn resolve_transcript(name: &str) -> PathBuf {
+    let path = PathBuf::from(name);
+    if path.exists() {
+        return path;
+    }
+    let named = env::temp_dir().join(format!("{}{}", TRANSCRIPT_NAME, name));
+    if named.exists() {
+        named
+    } else {
+        path
     }
 }
 
-fn show_history(conversation_state: &ConversationState) {
-    let tmp_dir = env::temp_dir();
-    let tmp_path = tmp_dir.join("ask_hist");
+fn diff_transcripts(a: &str, b: &str, include_prompts: bool) {
+    let load = |name: &str| {
+        ConversationState::load(&resolve_transcript(name))
+            .unwrap_or_else(|e| fail(&format!("Unable to load transcript {}", name), Some(&e)))
+    };
+    let (convo_a, convo_b) = (load(a), load(b));
 
-    let mut content = String::new();
+    // Skip the seed; compare turn by turn
+    let turns = |convo: &ConversationState| -> Vec<Message> {
+        convo
+            .messages
+            .iter()
+            .skip(1)
+            .filter(|m| m.role == "assistant" || (include_prompts && m.role == "user"))
+            .cloned()
+            .collect()
+    };
+    let (turns_a, turns_b) = (turns(&convo_a), turns(&convo_b));
 
-    for message in &conversation_state.messages {
-        content.push_str("\n\n");
-        content.push_str(&horizontal_line('▃'));
-        content.push_str(&format!("▍{} ▐\n", message.role));
-        content.push_str(&horizontal_line('▀'));
-        content.push_str("\n");
-
-        if let Some(text) = message.content.as_str() {
-            content.push_str(text);
-        } else if let Some(array) = message.content.as_array() {
-            if let Some(first_item) = array.get(0) {
-                if let Some(text) = first_item.get("text").and_then(|v| v.as_str()) {
-                    content.push_str(text);
-                }
-            }
+    if convo_a.model != convo_b.model {
+        println!("model: {} -> {}", convo_a.model, convo_b.model);
+    }
+
+    for index in 0..turns_a.len().max(turns_b.len()) {
+        let (left, right) = (turns_a.get(index), turns_b.get(index));
+        let role = left.or(right).map(|m| m.role.as_str()).unwrap_or("");
+        let (left_text, right_text) = (
+            left.map(|m| m.text()).unwrap_or(""),
+            right.map(|m| m.text()).unwrap_or(""),
+        );
+
+        if left_text == right_text {
+            println!("=== {} #{}: identical", role, index + 1);
+            continue;
+        }
+
+        println!("=== {} #{}", role, index + 1);
+        for line in diff_lines(left_text, right_text) {
+            println!("{}", line);
         }
     }
+}
 
-    fs::write(&tmp_path, content).expect("Unable to write history file");
+// Minimal LCS line diff: unchanged lines are indented, removals "-", additions "+"
+fn diff_lines(a: &str, b: &str) -> Vec<String> {
+    let (a, b): (Vec<&str>, Vec<&str>) = (a.lines().collect(), b.lines().collect());
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
 
-    let editor = env::var("EDITOR").unwrap_or_else(|_| "more".to_string());
-    ProcessCommand::new(editor)
-        .arg(&tmp_path)
-        .status()
-        .expect("Failed to open editor");
+    let (mut i, mut j, mut out) = (0, 0, Vec::new());
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    out
+}
 
-    fs::remove_file(&tmp_path).expect("Unable to delete temporary history file");
+// None when stdout isn't a terminal; width-dependent formatting is skipped then
+fn terminal_width() -> Option<usize> {
+    term_size::dimensions_stdout().map(|(w, _)| w)
 }
 
+// Full-width on a terminal; a short marker otherwise so piped output isn't padded
 fn horizontal_line(ch: char) -> String {
-    let columns = term_size::dimensions_stdout().map(|(w, _)| w).unwrap_or(80);
+    let columns = terminal_width().unwrap_or(PIPED_BANNER_WIDTH);
     ch.to_string().repeat(columns)
 }
 
 fn handle_recursive_mode(
     conversation_state: &mut ConversationState,
-    transcript_path: &PathBuf,
+    transcript_path: &Path,
     user_input: String,
+    client: &Client,
+    options: &RequestOptions,
+    agent_options: &AgentOptions,
 ) {
+    // Without a terminal every confirmation would fail and be read as a rejection
+    if !options.interactive && !agent_options.assume_yes && !agent_options.dry_run {
+        fail(
+            "Agent mode needs a terminal to confirm commands. Pass --yes or --dry-run.",
+            None,
+        );
+    }
+
+    let mut steps = 0;
+    // Consecutive replies that had neither a command nor DONE
+    let mut no_command = 0;
+    // With --output-only-on-change, a step that repeats the previous one prints nothing
+    let mut previous_step: Option<String> = None;
+    let changed = |previous: &mut Option<String>, state: String| {
+        let is_new = !agent_options.only_on_change || previous.as_ref() != Some(&state);
+        *previous = Some(state);
+        is_new
+    };
+
+    let ask_for_command = if agent_options.json_protocol {
+        format!("Original task: {}. Suggest the next command to run. Reply with a JSON object: {{\"thought\": <your reasoning>, \"command\": <shell command or null>, \"done\": <true if the task is complete>}}.", user_input)
+    } else {
+        format!("Original task: {}. Suggest the next command to run. Format your response as: COMMAND: <command> followed by an explanation. Or say DONE if the task is complete.", user_input)
+    };
+
     loop {
-        // Get last AI message to check if it's already a command
-        let mut last_message = conversation_state.messages.last().unwrap();
-        let mut response = last_message.content.as_str().unwrap_or("");
+        if options.budget_exhausted() {
+            println!(
+                "Call budget exhausted: {} API calls over {} steps, stopping.",
+                options.api_calls.get(),
+                steps
+            );
+            break;
+        }
+
+        // Check if the last AI message is already a command
+        let mut action = next_agent_action(
+            conversation_state.messages.last().unwrap(),
+            agent_options.json_protocol,
+        );
 
         // Check if task is complete
-        if response.contains("DONE") {
+        if action == AgentAction::Done {
             println!("Task completed!");
             break;
         }
 
         // If the last message wasn't a command suggestion, ask for one
-        if !response.contains("COMMAND:") {
-            let input = Value::String(format!("Original task: {}. Suggest the next command to run. Format your response as: COMMAND: <command> followed by an explanation. Or say DONE if the task is complete.", user_input));
-            perform_request(input, conversation_state, transcript_path, "");
+        if action == AgentAction::None {
+            let input = Value::String(ask_for_command.clone());
+            perform_request(input, conversation_state, transcript_path, client, options);
 
-            // Update response with new AI message
-            last_message = conversation_state.messages.last().unwrap();
-            response = last_message.content.as_str().unwrap_or("");
+            action = next_agent_action(
+                conversation_state.messages.last().unwrap(),
+                agent_options.json_protocol,
+            );
 
             // If response is updated, we need to check for completion again
-            if response.contains("DONE") {
+            if action == AgentAction::Done {
                 println!("Task completed!");
                 break;
             }
+
+            if action == AgentAction::None {
+                no_command += 1;
+                // A protocol failure, not a finished task, so scripts see a non-zero exit
+                if no_command >= agent_options.max_no_command {
+                    fail(
+                        &format!(
+                            "Stopping: {} replies in a row without a command or DONE; the model isn't following the agent protocol.",
+                            no_command
+                        ),
+                        None,
+                    );
+                }
+                continue;
+            }
         }
+        no_command = 0;
+
+        if let AgentAction::Command(command) = &action {
+            steps += 1;
+            if agent_options.max_steps.is_some_and(|max| steps > max) {
+                println!("Step limit reached, stopping.");
+                break;
+            }
 
-        // Extract command
-        if let Some(cmd_start) = response.find("COMMAND:") {
-            let cmd_text = response[cmd_start..].lines().next().unwrap();
-            let command = cmd_text.trim_start_matches("COMMAND:").trim();
+            if let Some(pattern) = agent_options
+                .deny_commands
+                .iter()
+                .find(|pattern| pattern.is_match(command))
+            {
+                println!(
+                    "\n\nRefused command (matches --deny-commands {:?}): {}",
+                    pattern.as_str(),
+                    command
+                );
+                let input = Value::String(format!(
+                    "Command was refused without running: it matches the blocked pattern {:?}. Please suggest an alternative that avoids it.",
+                    pattern.as_str()
+                ));
+                perform_request(input, conversation_state, transcript_path, client, options);
+                continue;
+            }
+
+            if agent_options.dry_run {
+                if changed(&mut previous_step, command.clone()) {
+                    println!("\n\nProposed command (dry run, not executed): {}", command);
+                }
+                let input = Value::String(
+                    "Command was not executed (dry run). Assume it succeeded and suggest the next command, or say DONE if the task is complete.".to_string(),
+                );
+                perform_request(input, conversation_state, transcript_path, client, options);
+                continue;
+            }
 
             // Get user approval
-            let confirm = dialoguer::Confirm::new()
-                .with_prompt(format!("\n\nRun command: {}", command))
-                .default(false)
-                .interact()
-                .unwrap_or(false);
+            let proposed = command.as_str();
+            let mut command = command.clone();
+            let confirm = if agent_options.assume_yes {
+                true
+            } else if agent_options.edit_commands {
+                let choice = Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("\n\nRun command: {}", command))
+                    .default(0)
+                    .items(&["Run", "Edit, then run", "Reject"])
+                    .interact();
+                match choice {
+                    Ok(0) => true,
+                    // The edited command is what runs, and the model is told it was changed
+                    Ok(1) => match edit_command(&command) {
+                        Some(edited) => {
+                            command = edited;
+                            true
+                        }
+                        None => false,
+                    },
+                    _ => false,
+                }
+            } else {
+                dialoguer::Confirm::new()
+                    .with_prompt(format!("\n\nRun command: {}", command))
+                    .default(false)
+                    .interact()
+                    .unwrap_or(false)
+            };
+            let command = command.as_str();
+            let feedback =
+                |report: String| Value::String(command_feedback(proposed, command, report));
 
             if confirm {
                 // Execute command and capture output
-                match ProcessCommand::new("sh").arg("-c").arg(command).output() {
+                match ProcessCommand::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .envs(agent_options.env.iter().map(|(k, v)| (k, v)))
+                    .output()
+                {
                     Ok(output) => {
                         let stdout = String::from_utf8_lossy(&output.stdout);
                         let stderr = String::from_utf8_lossy(&output.stderr);
                         let result =
                             format!("Command output:\nstdout:\n{}\nstderr:\n{}", stdout, stderr);
-                        println!("{}", result);
+                        let step_state = format!("{}\n{}\n{}", command, output.status, result);
+                        if !changed(&mut previous_step, step_state) {
+                            // Same command and outcome as the last step, nothing new to show
+                        } else if agent_options.quiet {
+                            println!(
+                                "[step {}] {} -> {} ({} lines of output)",
+                                steps,
+                                command,
+                                output.status,
+                                stdout.lines().count() + stderr.lines().count()
+                            );
+                        } else {
+                            println!("{}", result);
+                        }
+
+                        // Stop early if the output matches the user-provided pattern
+                        if let Some(pattern) = &agent_options.stop_on {
+                            let combined = format!("{}\n{}", stdout, stderr);
+                            if let Some(found) = pattern.find(&combined) {
+                                println!(
+                                    "Stopping: command output matched --stop-on pattern ({:?}).",
+                                    found.as_str()
+                                );
+                                break;
+                            }
+                        }
 
                         // Pass result back to AI
-                        let input = Value::String(result);
-                        perform_request(input, conversation_state, transcript_path, "");
+                        let input = feedback(if agent_options.completion_only {
+                            feedback_without_echo(command, &stdout, &stderr)
+                        } else {
+                            result
+                        });
+                        perform_request(
+                            input,
+                            conversation_state,
+                            transcript_path,
+                            client,
+                            options,
+                        );
                     }
                     Err(e) => {
                         println!("Failed to execute command: {}", e);
-                        let input = Value::String(format!("Command failed: {}", e));
-                        perform_request(input, conversation_state, transcript_path, "");
+                        let input = feedback(format!("Command failed: {}", e));
+                        perform_request(
+                            input,
+                            conversation_state,
+                            transcript_path,
+                            client,
+                            options,
+                        );
                     }
                 }
             } else {
@@ -411,25 +3526,79 @@ fn handle_recursive_mode(
                 let input = Value::String(
                     format!("Command was rejected by user.\nFEEDBACK: {}\n\nPlease suggest an alternative.", comment).to_string(),
                 );
-                perform_request(input, conversation_state, transcript_path, "");
+                perform_request(input, conversation_state, transcript_path, client, options);
             }
         }
     }
 }
 
-fn delete_all_files(files: Vec<PathBuf>) {
-    // Delete all conversations
+// Opens $EDITOR, or vi since the file is meant to be changed; an emptied file drops the command.
+// The file is per-process and created fresh, so concurrent runs and planted symlinks can't interfere.
+fn edit_command(command: &str) -> Option<String> {
+    let tmp_path = env::temp_dir().join(format!("ask_command-{}.sh", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| writeln!(file, "{}", command))
+        .unwrap_or_else(|e| fail("Unable to write command file", Some(&e)));
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let status = ProcessCommand::new(editor)
+        .arg(&tmp_path)
+        .status()
+        .unwrap_or_else(|e| fail("Failed to open editor", Some(&e)));
+    let edited = fs::read_to_string(&tmp_path).unwrap_or_default();
+    fs::remove_file(&tmp_path)
+        .unwrap_or_else(|e| fail("Unable to delete temporary command file", Some(&e)));
+
+    let edited = edited.trim();
+    (status.success() && !edited.is_empty()).then(|| edited.to_string())
+}
+
+// --completion-only: drop echoed commands and empty sections from what the model reads
+fn feedback_without_echo(command: &str, stdout: &str, stderr: &str) -> String {
+    let stdout = strip_command_echo(command, stdout).trim_end();
+    let stderr = strip_command_echo(command, stderr).trim_end();
+    match (stdout.is_empty(), stderr.is_empty()) {
+        (true, true) => "Command output: (none)".to_string(),
+        (false, true) => format!("Command output:\n{}", stdout),
+        (true, false) => format!("Command output:\nstderr:\n{}", stderr),
+        (false, false) => format!("Command output:\nstdout:\n{}\nstderr:\n{}", stdout, stderr),
+    }
+}
+
+fn next_agent_action(message: &Message, json_protocol: bool) -> AgentAction {
+    // Only replies carry actions; the seed or a pending user turn means "ask again"
+    if message.role != "assistant" {
+        return AgentAction::None;
+    }
+    let response = message.text();
+
+    // A reply that isn't valid JSON falls through to the text markers
+    json_protocol
+        .then(|| parse_agent_json(response))
+        .flatten()
+        .unwrap_or_else(|| parse_agent_response(response))
+}
+
+fn delete_all_files(files: &[PathBuf]) {
+    // Delete all listed conversations
     let confirm = dialoguer::Confirm::new()
-        .with_prompt("Are you sure you want to delete all conversations?")
+        .with_prompt(format!(
+            "Are you sure you want to delete all {} listed conversations?",
+            files.len()
+        ))
         .default(false)
         .interact()
         .unwrap_or(false);
 
     if confirm {
         let mut deleted_count = 0;
-        for file in &files {
+        for file in files {
             if let Err(e) = fs::remove_file(file) {
-                eprintln!("Failed to delete {}: {}", file.display(), e);
+                report_error(&format!("Failed to delete {}", file.display()), Some(&e));
             } else {
                 deleted_count += 1;
             }
@@ -440,11 +3609,21 @@ fn delete_all_files(files: Vec<PathBuf>) {
     }
 }
 
-fn manage_ongoing_convos(current_convo: &mut ConversationState, current_transcript_path: &PathBuf) {
+fn manage_ongoing_convos(
+    current_convo: &mut ConversationState,
+    current_transcript_path: &Path,
+    max_conversations: usize,
+    strict_roles: bool,
+) {
     let transcript_folder = env::temp_dir();
-    let entries = fs::read_dir(&transcript_folder).unwrap();
+    let entries = fs::read_dir(&transcript_folder).unwrap_or_else(|e| {
+        fail(
+            &format!("Unable to read {}", transcript_folder.display()),
+            Some(&e),
+        )
+    });
 
-    let files: Vec<PathBuf> = entries
+    let mut files: Vec<PathBuf> = entries
         .filter_map(|e| e.ok())
         .map(|e| e.path())
         .filter(|p| {
@@ -460,18 +3639,29 @@ fn manage_ongoing_convos(current_convo: &mut ConversationState, current_transcri
         return;
     }
 
+    // Most recent first; only the listed ones are ever read
+    files.sort_by_cached_key(|file| {
+        std::cmp::Reverse(fs::metadata(file).and_then(|m| m.modified()).ok())
+    });
+    let listed = files.len().min(max_conversations);
+    if listed < files.len() {
+        println!(
+            "Showing the {} most recent of {} conversations ({} older ones hidden, see --max-conversations).",
+            listed,
+            files.len(),
+            files.len() - listed
+        );
+    }
+
     // Prepare options for dialoguer
-    let mut options: Vec<String> = files
+    let mut options: Vec<String> = files[..listed]
         .iter()
         .map(|file| {
-            let data = fs::read_to_string(file).unwrap_or_default();
-            let convo: ConversationState =
-                serde_json::from_str(&data).unwrap_or_else(|_| ConversationState {
-                    model: "".to_string(),
-                    messages: vec![],
-                });
+            let convo = ConversationState::load(file).unwrap_or_default();
             let first_message = convo.messages.get(1); // Use get to avoid panicking
-            let content = if let Some(msg) = first_message {
+            let content = if let Some(title) = &convo.title {
+                title.as_str()
+            } else if let Some(msg) = first_message {
                 msg.content.as_str().unwrap_or("")
             } else {
                 ""
@@ -491,7 +3681,7 @@ fn manage_ongoing_convos(current_convo: &mut ConversationState, current_transcri
         .collect();
 
     //Add special helper option
-    options.insert(0, ">>> Delete All Conversations".to_string());
+    options.insert(0, format!(">>> Delete All {} Listed Conversations", listed));
 
     let selection = Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Select an option to manage")
@@ -501,7 +3691,8 @@ fn manage_ongoing_convos(current_convo: &mut ConversationState, current_transcri
 
     if let Ok(index) = selection {
         if index == 0 {
-            delete_all_files(files);
+            // Conversations hidden by --max-conversations were never shown, so they stay
+            delete_all_files(&files[..listed]);
             return;
         }
 
@@ -509,40 +3700,110 @@ fn manage_ongoing_convos(current_convo: &mut ConversationState, current_transcri
         let action = Select::with_theme(&ColorfulTheme::default())
             .with_prompt("Choose an action")
             .default(0)
-            .items(&["Delete", "Copy to Current Conversation", "Cancel"])
+            .items(&[
+                "Delete",
+                "Copy to Current Conversation",
+                "Merge with Current by Time into a New Conversation",
+                "Rename",
+                "Cancel",
+            ])
             .interact();
 
         match action {
             Ok(0) => {
                 // Delete the selected conversation
                 if let Err(e) = fs::remove_file(selected_file) {
-                    println!("Failed to delete conversation: {}", e);
+                    report_error("Failed to delete conversation", Some(&e));
                 } else {
                     println!("Conversation deleted successfully.");
                 }
             }
             Ok(1) => {
                 // Copy the selected conversation to current conversation
-                let data = fs::read_to_string(selected_file).unwrap_or_default();
-                let convo_to_copy: ConversationState =
-                    serde_json::from_str(&data).unwrap_or_else(|_| ConversationState {
-                        model: "".to_string(),
-                        messages: vec![],
-                    });
+                let convo_to_copy = ConversationState::load(selected_file).unwrap_or_default();
 
                 if convo_to_copy.model != current_convo.model {
                     println!("Cannot copy conversation: Model mismatch.");
                     return;
                 }
+                if let (true, Err(e)) = (strict_roles, check_roles(&convo_to_copy.messages)) {
+                    report_error("Cannot copy conversation", Some(&e));
+                    return;
+                }
 
                 current_convo
                     .messages
                     .extend(convo_to_copy.messages.iter().skip(1).cloned()); // Skip initial message
-                let conversation_json = serde_json::to_string(&current_convo).unwrap();
-                fs::write(current_transcript_path, conversation_json)
-                    .expect("Unable to write transcript file");
+                current_convo
+                    .save(current_transcript_path)
+                    .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
                 println!("Conversation copied successfully.");
             }
+            Ok(2) => {
+                // Interleave both threads chronologically; neither original is changed
+                let other = ConversationState::load(selected_file).unwrap_or_default();
+
+                if other.model != current_convo.model {
+                    println!("Cannot merge conversations: Model mismatch.");
+                    return;
+                }
+                if let (true, Err(e)) = (strict_roles, check_roles(&other.messages)) {
+                    report_error("Cannot merge conversations", Some(&e));
+                    return;
+                }
+
+                let mut merged = current_convo.clone();
+                merged.title = None;
+                merged.messages.truncate(1);
+                merged.messages.extend(merge_by_timestamp(
+                    current_convo.messages.get(1..).unwrap_or_default(),
+                    other.messages.get(1..).unwrap_or_default(),
+                ));
+                let merged_path = current_transcript_path.with_file_name(format!(
+                    "{}merged-{}{}",
+                    TRANSCRIPT_NAME,
+                    unix_now(),
+                    TranscriptFormat::from_path(current_transcript_path).extension()
+                ));
+                merged
+                    .save(&merged_path)
+                    .unwrap_or_else(|e| fail("Unable to write transcript file", Some(&e)));
+                println!(
+                    "Conversations merged into {} ({} messages).",
+                    merged_path.display(),
+                    merged.messages.len()
+                );
+            }
+            Ok(3) => {
+                // Rename the selected conversation, keeping the transcript prefix
+                let new_name = dialoguer::Input::<String>::new()
+                    .with_prompt("New conversation name")
+                    .interact()
+                    .unwrap_or_default();
+                let new_name = new_name.trim();
+
+                if new_name.is_empty() || new_name.contains('/') {
+                    println!("Invalid conversation name.");
+                    return;
+                }
+
+                let new_path = selected_file.with_file_name(format!(
+                    "{}{}{}",
+                    TRANSCRIPT_NAME,
+                    new_name,
+                    TranscriptFormat::from_path(selected_file).extension()
+                ));
+                if new_path.exists() {
+                    println!("A conversation named '{}' already exists.", new_name);
+                    return;
+                }
+
+                if let Err(e) = fs::rename(selected_file, &new_path) {
+                    report_error("Failed to rename conversation", Some(&e));
+                } else {
+                    println!("Conversation renamed successfully.");
+                }
+            }
             _ => {
                 // Cancelled
                 println!("Action cancelled.");
@@ -0,0 +1,165 @@
+use ask_rs::agent::{
+    command_feedback, cut_after_first_command, parse_agent_json, parse_agent_response,
+    strip_command_echo, AgentAction,
+};
+
+fn command(text: &str) -> AgentAction {
+    AgentAction::Command(text.to_string())
+}
+
+#[test]
+fn command_on_its_line() {
+    assert_eq!(
+        parse_agent_response("COMMAND: ls -la\nThis lists the directory."),
+        command("ls -la")
+    );
+    assert_eq!(
+        parse_agent_response("Let's look first. COMMAND: cat Cargo.toml"),
+        command("cat Cargo.toml")
+    );
+}
+
+#[test]
+fn command_on_the_following_lines() {
+    assert_eq!(
+        parse_agent_response("COMMAND:\n\ngit status\nChecks the tree."),
+        command("git status")
+    );
+    assert_eq!(
+        parse_agent_response("COMMAND:\n```sh\ncd src &&\n  grep -rn TODO .\n```\nFinds TODOs."),
+        command("cd src &&\ngrep -rn TODO .")
+    );
+}
+
+#[test]
+fn done_in_prose_is_not_completion() {
+    assert_eq!(
+        parse_agent_response("I'll say DONE once the build passes. COMMAND: cargo build"),
+        command("cargo build")
+    );
+    assert_eq!(
+        parse_agent_response("Nothing is DONE yet, what should I check?"),
+        AgentAction::None
+    );
+    assert_eq!(
+        parse_agent_response("DONE-ish, but not quite."),
+        AgentAction::None
+    );
+}
+
+#[test]
+fn done_marker_lines() {
+    for reply in [
+        "DONE",
+        "**DONE**",
+        "The files are renamed.\nDONE.",
+        "DONE: all tests pass",
+    ] {
+        assert_eq!(
+            parse_agent_response(reply),
+            AgentAction::Done,
+            "{:?}",
+            reply
+        );
+    }
+}
+
+#[test]
+fn first_marker_wins() {
+    assert_eq!(
+        parse_agent_response("COMMAND: make\nRun it, then I will reply\nDONE"),
+        command("make")
+    );
+    assert_eq!(
+        parse_agent_response("DONE\nCOMMAND: rm -rf build"),
+        AgentAction::Done
+    );
+}
+
+#[test]
+fn missing_or_empty_command() {
+    assert_eq!(parse_agent_response(""), AgentAction::None);
+    assert_eq!(parse_agent_response("COMMAND:   "), AgentAction::None);
+    assert_eq!(
+        parse_agent_response("Could you tell me which directory?"),
+        AgentAction::None
+    );
+}
+
+#[test]
+fn json_protocol() {
+    assert_eq!(
+        parse_agent_json(r#"{"thought":"look around","command":" ls ","done":false}"#),
+        Some(command("ls"))
+    );
+    assert_eq!(
+        parse_agent_json(r#"{"thought":"finished","command":null,"done":true}"#),
+        Some(AgentAction::Done)
+    );
+    assert_eq!(
+        parse_agent_json(r#"{"thought":"need more info"}"#),
+        Some(AgentAction::None)
+    );
+    assert_eq!(parse_agent_json("COMMAND: ls"), None);
+    assert_eq!(parse_agent_json("[1, 2]"), None);
+}
+
+#[test]
+fn backticks_around_the_command_are_stripped() {
+    assert_eq!(
+        parse_agent_response("COMMAND: `ls -la`\nLists everything."),
+        command("ls -la")
+    );
+    assert_eq!(
+        parse_agent_response("COMMAND: ```sh grep -rn TODO src```"),
+        command("grep -rn TODO src")
+    );
+    assert_eq!(
+        parse_agent_response("COMMAND: echo `date`"),
+        command("echo `date`")
+    );
+    assert_eq!(
+        parse_agent_json(r#"{"command":"`cargo test`","done":false}"#),
+        Some(command("cargo test"))
+    );
+}
+
+#[test]
+fn echoed_command_lines_are_dropped() {
+    assert_eq!(
+        strip_command_echo("ls -la", "$ ls -la\n+ ls -la\ntotal 0\n"),
+        "total 0\n"
+    );
+    assert_eq!(strip_command_echo("echo hi", "echo hi\n"), "");
+    // Output that merely starts like the command is real output
+    assert_eq!(
+        strip_command_echo("date", "date: invalid\n"),
+        "date: invalid\n"
+    );
+    assert_eq!(strip_command_echo("ls", "a\nls\n"), "a\nls\n");
+}
+
+#[test]
+fn reply_is_cut_before_a_second_command() {
+    assert_eq!(
+        cut_after_first_command("Let me look.\nCOMMAND: ls\nLists files.\nCOMMAND: rm -rf build\n"),
+        "Let me look.\nCOMMAND: ls\nLists files."
+    );
+    assert_eq!(
+        cut_after_first_command("Let me look.\nCOMMAND: ls"),
+        "Let me look.\nCOMMAND: ls"
+    );
+    assert_eq!(cut_after_first_command("DONE"), "DONE");
+}
+
+#[test]
+fn edited_commands_are_named_in_the_feedback() {
+    assert_eq!(
+        command_feedback("ls", "ls -la", "Command output: (none)".to_string()),
+        "The user edited the command to: ls -la\n\nCommand output: (none)"
+    );
+    assert_eq!(
+        command_feedback("ls", "ls", "Command output: (none)".to_string()),
+        "Command output: (none)"
+    );
+}
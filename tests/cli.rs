@@ -0,0 +1,893 @@
+use httpmock::prelude::*;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Each test gets its own temp dir; the binary keys its transcript on our pid
+fn sandbox(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ask_rs-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn transcript_path(dir: &Path) -> PathBuf {
+    dir.join(format!("gpt_transcript-{}", std::process::id()))
+}
+
+fn run_ask(dir: &Path, base_url: &str, stdin: &str, args: &[&str]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ask_rs"))
+        .args(args)
+        .env("OPENAI_API_KEY", "test-key")
+        .env("OPENAI_BASE_URL", base_url)
+        .env("TMPDIR", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn piped_prompt_is_sent_and_reply_persisted() {
+    let dir = sandbox("persist");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body_partial(
+                json!({
+                    "model": "o1-mini",
+                    "messages": [
+                        { "role": "user", "content": ask_rs::SEED_PROMPT },
+                        { "role": "user", "content": "Hi there" },
+                    ],
+                })
+                .to_string(),
+            );
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#);
+    });
+
+    let output = run_ask(&dir, &server.base_url(), "Hi there", &[]);
+
+    mock.assert();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello!\n");
+
+    let transcript: Value =
+        serde_json::from_str(&std::fs::read_to_string(transcript_path(&dir)).unwrap()).unwrap();
+    assert_eq!(transcript["model"], "o1-mini");
+    assert_eq!(transcript["messages"][1]["content"], "Hi there");
+    assert_eq!(transcript["messages"][2]["role"], "assistant");
+    assert_eq!(transcript["messages"][2]["content"], "Hello!");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn last_message_does_not_touch_transcript() {
+    let dir = sandbox("last");
+    let path = transcript_path(&dir);
+    let transcript = json!({
+        "model": "o1-mini",
+        "messages": [
+            { "role": "user", "content": ask_rs::SEED_PROMPT },
+            { "role": "user", "content": "Hi there" },
+            { "role": "assistant", "content": "Hello!" },
+        ],
+    })
+    .to_string();
+    std::fs::write(&path, &transcript).unwrap();
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    // Nothing listens on this port, so any request would fail loudly
+    let output = run_ask(&dir, "http://127.0.0.1:9", "", &["-l"]);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\"Hello!\"\n");
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().modified().unwrap(),
+        modified
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), transcript);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn from_message_keeps_pinned_turns() {
+    let dir = sandbox("from");
+    let path = transcript_path(&dir);
+    let transcript = json!({
+        "version": ask_rs::TRANSCRIPT_VERSION,
+        "model": "o1-mini",
+        "messages": [
+            { "role": "user", "content": ask_rs::SEED_PROMPT },
+            { "role": "user", "content": "Dropped" },
+            { "role": "user", "content": "Keep this", "pinned": true },
+            { "role": "assistant", "content": "Also dropped" },
+        ],
+    });
+    std::fs::write(&path, transcript.to_string()).unwrap();
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body_partial(
+                json!({
+                    "messages": [
+                        { "role": "user", "content": ask_rs::SEED_PROMPT },
+                        { "role": "user", "content": "Keep this" },
+                        { "role": "user", "content": "Next" },
+                    ],
+                })
+                .to_string(),
+            );
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Ok."}}]}"#);
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Next",
+        &["--from-message", "0", "--show-trim"],
+    );
+
+    mock.assert();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Trimmed: messages 1, 3 are not sent (--from-message 0)."));
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Next",
+        &["--from-message", "0", "--truncate"],
+    );
+
+    assert!(output.status.success());
+    let transcript: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let contents: Vec<&str> = transcript["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, [ask_rs::SEED_PROMPT, "Keep this", "Next", "Ok."]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn empty_piped_input_fails_instead_of_opening_history() {
+    let dir = sandbox("empty");
+
+    let output = run_ask(&dir, "http://127.0.0.1:9", "", &[]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No input provided"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn batch_sends_each_line_without_touching_transcript() {
+    let dir = sandbox("batch");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#);
+    });
+    let prompts = dir.join("prompts.txt");
+    std::fs::write(&prompts, "first\n\nsecond\n").unwrap();
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "",
+        &[
+            "--batch",
+            prompts.to_str().unwrap(),
+            "--batch-concurrency",
+            "2",
+        ],
+    );
+
+    mock.assert_hits(2);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "===== line 1 =====\nHello!\n===== line 3 =====\nHello!\n"
+    );
+    assert!(!transcript_path(&dir).exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn piped_history_uses_thin_banners() {
+    let dir = sandbox("banner");
+    let transcript = json!({
+        "model": "gpt-4o",
+        "messages": [
+            { "role": "system", "content": "seed" },
+            { "role": "user", "content": "Hi there" },
+        ],
+    });
+    std::fs::write(transcript_path(&dir), transcript.to_string()).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ask_rs"))
+        .arg("--force-interactive")
+        .env("OPENAI_API_KEY", "test-key")
+        .env("TMPDIR", &dir)
+        .env("XDG_CONFIG_HOME", &dir)
+        .env("EDITOR", "cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdin.take());
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("▃▃▃▍user ▐\n▀▀▀\nHi there"));
+    assert!(stdout.lines().all(|line| line.chars().count() <= 12));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn suffix_is_sent_but_not_saved() {
+    let dir = sandbox("suffix");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body_partial(
+                json!({
+                    "messages": [
+                        { "role": "user", "content": ask_rs::SEED_PROMPT },
+                        { "role": "user", "content": "Hi there\n\nAssume Arch Linux." },
+                    ],
+                })
+                .to_string(),
+            );
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#);
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Hi there",
+        &["--suffix", "Assume Arch Linux."],
+    );
+
+    mock.assert();
+    assert!(output.status.success());
+    let transcript: Value =
+        serde_json::from_str(&std::fs::read_to_string(transcript_path(&dir)).unwrap()).unwrap();
+    assert_eq!(transcript["messages"][1]["content"], "Hi there");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn check_key_tells_rejection_from_connection_errors() {
+    let dir = sandbox("checkkey");
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/v1/models");
+        then.status(401);
+    });
+
+    let rejected = run_ask(&dir, &server.base_url(), "", &["--check-key"]);
+    let unreachable = run_ask(&dir, "http://127.0.0.1:9", "", &["--check-key"]);
+
+    assert!(!rejected.status.success());
+    assert!(String::from_utf8_lossy(&rejected.stderr).contains("Key was rejected"));
+    assert!(!unreachable.status.success());
+    let stderr = String::from_utf8_lossy(&unreachable.stderr);
+    assert!(stderr.contains("Connection error"));
+    assert!(!stderr.contains("rejected"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dump_config_shows_settings_resolved_late() {
+    let dir = sandbox("dump");
+    let saved = dir.join("request.json");
+
+    let output = run_ask(
+        &dir,
+        "http://127.0.0.1:9",
+        "",
+        &[
+            "--dump-config",
+            "--no-network",
+            "--save-request",
+            saved.to_str().unwrap(),
+        ],
+    );
+
+    assert!(output.status.success());
+    let resolved: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(resolved["save_request"], saved.to_str().unwrap());
+    assert_eq!(resolved["offline"], true);
+    assert_eq!(resolved["strict_roles"], true);
+    assert!(!resolved["api_key"].as_str().unwrap().contains("test-key"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn confirm_each_previews_the_prompt_as_sent() {
+    let dir = sandbox("confirm");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Hi."}}]}"#);
+    });
+
+    // Without a terminal the confirmation reads as a no
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Hello",
+        &[
+            "--confirm-each",
+            "--model",
+            "gpt-4o",
+            "--suffix",
+            "Answer briefly.",
+            "--prepend-system",
+            "Be terse.",
+        ],
+    );
+
+    mock.assert_hits(0);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[system] Be terse.\nHello\n\nAnswer briefly.\n"));
+    assert!(stdout.contains("Request cancelled."));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn no_network_refuses_to_send() {
+    let dir = sandbox("offline");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#);
+    });
+
+    let output = run_ask(&dir, &server.base_url(), "Hi there", &["--no-network"]);
+
+    mock.assert_hits(0);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("network access is disabled"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_schema_is_sent_and_reply_validated() {
+    let dir = sandbox("schema");
+    let schema = json!({
+        "type": "object",
+        "properties": { "answer": { "type": "integer" } },
+        "required": ["answer"],
+        "additionalProperties": false,
+    });
+    let schema_path = dir.join("schema.json");
+    std::fs::write(&schema_path, schema.to_string()).unwrap();
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions").json_body_partial(
+            json!({
+                "response_format": {
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema, "strict": true },
+                },
+            })
+            .to_string(),
+        );
+        then.status(200).body(
+            r#"{"choices":[{"message":{"role":"assistant","content":"{\"answer\":\"forty-two\"}"}}]}"#,
+        );
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "What is 6 times 7?",
+        &[
+            "--model",
+            "gpt-4o",
+            "--json-schema",
+            schema_path.to_str().unwrap(),
+        ],
+    );
+
+    mock.assert();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not match the JSON schema"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn denied_agent_command_is_refused_even_with_yes() {
+    let dir = sandbox("deny");
+    let marker = dir.join("ran");
+    let command = format!("rm -rf {0} ; touch {0}", marker.display());
+    let server = MockServer::start();
+    let refused = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .body_contains("refused without running");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"DONE"}}]}"#);
+    });
+    let proposal = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).json_body(json!({
+            "choices": [{ "message": { "role": "assistant", "content": format!("COMMAND: {}", command) } }],
+        }));
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Clean up",
+        &["-r", "--yes", "--deny-commands", r"rm\s+-rf"],
+    );
+
+    refused.assert();
+    proposal.assert_hits(1);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Refused command"));
+    assert!(!marker.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn agent_runs_a_command_after_a_preamble() {
+    let dir = sandbox("preamble");
+    let first = dir.join("first");
+    let second = dir.join("second");
+    let server = MockServer::start();
+    let done = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .body_contains("Command output");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"DONE"}}]}"#);
+    });
+    let proposal = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).json_body(json!({
+            "choices": [{ "message": { "role": "assistant", "content": format!(
+                "Let me create it.\nCOMMAND: touch {}\nThen the next one.\nCOMMAND: touch {}",
+                first.display(),
+                second.display()
+            ) } }],
+        }));
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Create the file",
+        &["-r", "--yes"],
+    );
+
+    proposal.assert_hits(1);
+    done.assert();
+    assert!(output.status.success());
+    assert!(first.exists());
+    assert!(!second.exists());
+    let transcript = std::fs::read_to_string(transcript_path(&dir)).unwrap();
+    assert!(!transcript.contains("Then the next one.\\nCOMMAND"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn backticked_agent_command_runs_without_the_backticks() {
+    let dir = sandbox("ticks");
+    let server = MockServer::start();
+    // Run with the backticks, sh would substitute `echo hi` and then fail to find `hi`
+    let done = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .body_contains(r#"stdout:\nhi\n\nstderr:\n""#);
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"DONE"}}]}"#);
+    });
+    let proposal = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(
+            r#"{"choices":[{"message":{"role":"assistant","content":"COMMAND: `echo hi`"}}]}"#,
+        );
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Say hi",
+        &["-r", "--yes", "--max-steps", "2"],
+    );
+
+    proposal.assert_hits(1);
+    done.assert();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Task completed!"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn jsonl_export_keeps_only_fine_tuning_keys() {
+    let dir = sandbox("jsonl");
+    let transcript = json!({
+        "version": ask_rs::TRANSCRIPT_VERSION,
+        "model": "gpt-4o",
+        "messages": [
+            { "role": "user", "content": ask_rs::SEED_PROMPT },
+            { "role": "user", "content": "Hi there", "pinned": true, "timestamp": 1700000000 },
+            { "role": "assistant", "content": "Hello!", "model": "gpt-4o-mini", "timestamp": 1700000042 },
+        ],
+    });
+    std::fs::write(transcript_path(&dir), transcript.to_string()).unwrap();
+    let export = dir.join("out.jsonl");
+
+    let output = run_ask(
+        &dir,
+        "http://127.0.0.1:9",
+        "",
+        &["--export-jsonl", export.to_str().unwrap()],
+    );
+
+    assert!(output.status.success());
+    let line: Value = serde_json::from_str(&std::fs::read_to_string(&export).unwrap()).unwrap();
+    for message in line["messages"].as_array().unwrap() {
+        let keys: Vec<&String> = message.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["content", "role"]);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn read_features_refuse_a_conversation_without_replies() {
+    let dir = sandbox("noreply");
+    let transcript = json!({
+        "version": ask_rs::TRANSCRIPT_VERSION,
+        "model": "o1-mini",
+        "messages": [{ "role": "user", "content": ask_rs::SEED_PROMPT }],
+    });
+    std::fs::write(transcript_path(&dir), transcript.to_string()).unwrap();
+    let export = dir.join("out.jsonl");
+
+    for args in [vec!["-l"], vec!["--export-jsonl", export.to_str().unwrap()]] {
+        let output = run_ask(&dir, "http://127.0.0.1:9", "", &args);
+        assert!(!output.status.success(), "{:?}", args);
+        assert!(output.stdout.is_empty());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("No assistant reply yet"));
+    }
+    assert!(!export.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn persona_seeds_a_new_conversation() {
+    let dir = sandbox("persona");
+    std::fs::create_dir_all(dir.join("ask")).unwrap();
+    std::fs::write(
+        dir.join("ask/config.toml"),
+        "[personas.reviewer]\nprompt = \"You review code.\"\nmodel = \"gpt-4o\"\ntemperature = 0.2\n",
+    )
+    .unwrap();
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body_partial(
+                json!({
+                    "model": "gpt-4o",
+                    "temperature": 0.2,
+                    "messages": [
+                        { "role": "system", "content": "You review code." },
+                        { "role": "user", "content": "fn main() {}" },
+                    ],
+                })
+                .to_string(),
+            );
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Looks fine."}}]}"#);
+    });
+
+    let unknown = run_ask(
+        &dir,
+        &server.base_url(),
+        "fn main() {}",
+        &["--persona", "poet"],
+    );
+    assert!(!unknown.status.success());
+    assert!(String::from_utf8_lossy(&unknown.stderr).contains("available: reviewer"));
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "fn main() {}",
+        &["--persona", "reviewer"],
+    );
+
+    mock.assert();
+    assert!(output.status.success());
+    let transcript: Value =
+        serde_json::from_str(&std::fs::read_to_string(transcript_path(&dir)).unwrap()).unwrap();
+    assert_eq!(transcript["persona"], "reviewer");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ensure_conversation_creates_the_seed_once() {
+    let dir = sandbox("ensure");
+    let path = dir.join("gpt_transcript-notes");
+    let args = ["--name", "notes", "--ensure-conversation"];
+
+    // Nothing listens on this port, so any request would fail loudly
+    let output = run_ask(&dir, "http://127.0.0.1:9", "", &args);
+    assert!(output.status.success());
+    let transcript: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(transcript["messages"].as_array().unwrap().len(), 1);
+
+    let mut existing = transcript.clone();
+    existing["messages"]
+        .as_array_mut()
+        .unwrap()
+        .push(json!({ "role": "user", "content": "Keep me" }));
+    std::fs::write(&path, existing.to_string()).unwrap();
+
+    let output = run_ask(&dir, "http://127.0.0.1:9", "", &args);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        existing.to_string()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn limited_output_keeps_the_full_reply_in_the_transcript() {
+    let dir = sandbox("limit");
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"one\ntwo\nthree"}}]}"#);
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Count to three",
+        &["--limit-output-lines", "1"],
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "one\n(2 more lines, see -l)\n"
+    );
+    let transcript: Value =
+        serde_json::from_str(&std::fs::read_to_string(transcript_path(&dir)).unwrap()).unwrap();
+    assert_eq!(transcript["messages"][2]["content"], "one\ntwo\nthree");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fifo_without_a_reader_is_skipped() {
+    let dir = sandbox("fifo");
+    let fifo = dir.join("replies");
+    assert!(std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"Hi."}}]}"#);
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Hello",
+        &["--fifo", fifo.to_str().unwrap()],
+    );
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hi.\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No reader on"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn command_output_is_added_to_the_prompt() {
+    let dir = sandbox("context");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions").json_body_partial(
+            json!({
+                "messages": [
+                    { "role": "user", "content": ask_rs::SEED_PROMPT },
+                    { "role": "user", "content": "Explain this\n\nOutput of `echo hello`:\n```\nhello\n```" },
+                ],
+            })
+            .to_string(),
+        );
+        then.status(200)
+            .body(r#"{"choices":[{"message":{"role":"assistant","content":"It greets."}}]}"#);
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Explain this",
+        &["--context-from-command", "echo hello"],
+    );
+
+    mock.assert();
+    assert!(output.status.success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn history_banners_show_model_and_time() {
+    let dir = sandbox("stamps");
+    let transcript = json!({
+        "model": "gpt-4o",
+        "messages": [
+            { "role": "system", "content": "seed" },
+            { "role": "user", "content": "Hi there", "timestamp": 1700000000 },
+            { "role": "assistant", "content": "Hello!", "model": "gpt-4o-mini", "timestamp": 1700000042 },
+        ],
+    });
+    std::fs::write(transcript_path(&dir), transcript.to_string()).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ask_rs"))
+        .args(["--force-interactive", "--history-format", "markdown"])
+        .env("OPENAI_API_KEY", "test-key")
+        .env("TMPDIR", &dir)
+        .env("XDG_CONFIG_HOME", &dir)
+        .env("EDITOR", "cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdin.take());
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\n## system\n"));
+    assert!(stdout.contains("\n## user 2023-11-14 22:13 UTC\n"));
+    assert!(stdout.contains("\n## assistant (gpt-4o-mini) 2023-11-14 22:14 UTC\n"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn history_grep_keeps_only_matching_turns() {
+    let dir = sandbox("grep");
+    let transcript = json!({
+        "model": "gpt-4o",
+        "messages": [
+            { "role": "system", "content": "seed" },
+            { "role": "user", "content": "How do I list files?" },
+            { "role": "assistant", "content": "Use LS." },
+            { "role": "user", "content": "Thanks" },
+        ],
+    });
+    std::fs::write(transcript_path(&dir), transcript.to_string()).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ask_rs"))
+        .args(["--force-interactive", "--history-grep", "ls"])
+        .env("OPENAI_API_KEY", "test-key")
+        .env("TMPDIR", &dir)
+        .env("XDG_CONFIG_HOME", &dir)
+        .env("EDITOR", "cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdin.take());
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("(3 of 4 messages hidden by --history-grep)\n"));
+    assert!(stdout.contains("Use LS."));
+    assert!(!stdout.contains("Thanks") && !stdout.contains("list files"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn transcript_template_names_the_file() {
+    let dir = sandbox("template");
+    std::fs::create_dir_all(dir.join("ask")).unwrap();
+    std::fs::write(
+        dir.join("ask/config.toml"),
+        "transcript_template = \"{name}-{model}\"\n",
+    )
+    .unwrap();
+
+    let output = run_ask(
+        &dir,
+        "http://127.0.0.1:9",
+        "",
+        &["--name", "notes", "--ensure-conversation"],
+    );
+
+    assert!(output.status.success());
+    assert!(dir.join("gpt_transcript-notes-o1-mini").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn agent_stops_when_the_model_never_proposes_a_command() {
+    let dir = sandbox("nocommand");
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(
+            r#"{"choices":[{"message":{"role":"assistant","content":"Let me think about it."}}]}"#,
+        );
+    });
+
+    let output = run_ask(
+        &dir,
+        &server.base_url(),
+        "Tidy the repo",
+        &["-r", "--yes", "--max-no-command", "2"],
+    );
+
+    // The task itself, then one re-ask before giving up
+    mock.assert_hits(2);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("isn't following the agent protocol"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,561 @@
+use ask_rs::{Client, ClientConfig, ConversationState, Message};
+use httpmock::prelude::*;
+use serde_json::json;
+
+const REPLY: &str = r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}]}"#;
+
+fn client_for(server: &MockServer, model: &str) -> Client {
+    let mut config = ClientConfig::new("test-key", model);
+    config.base_url = server.base_url();
+    Client::new(config)
+}
+
+#[test]
+fn o1_request_omits_sampling_parameters() {
+    let server = MockServer::start();
+    let state = ConversationState::new("o1-mini");
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .header("Authorization", "Bearer test-key")
+            .json_body(json!({
+                "model": "o1-mini",
+                "messages": [
+                    { "role": "user", "content": ask_rs::SEED_PROMPT },
+                ],
+            }));
+        then.status(200).body(REPLY);
+    });
+
+    client_for(&server, "o1-mini")
+        .send(&state.messages)
+        .unwrap();
+
+    mock.assert();
+}
+
+#[test]
+fn chat_request_includes_sampling_parameters() {
+    let server = MockServer::start();
+    let mut state = ConversationState::new("gpt-4o");
+    state.messages.push(Message::new("user", "Hi there"));
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body(json!({
+                "model": "gpt-4o",
+                "messages": [
+                    { "role": "system", "content": ask_rs::SEED_PROMPT },
+                    { "role": "user", "content": "Hi there" },
+                ],
+                "max_tokens": 2048,
+                "temperature": 0.6,
+            }));
+        then.status(200).body(REPLY);
+    });
+
+    client_for(&server, "gpt-4o").send(&state.messages).unwrap();
+
+    mock.assert();
+}
+
+#[test]
+fn canned_response_is_parsed_and_round_trips_through_transcript() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(REPLY);
+    });
+
+    let mut state = ConversationState::new("gpt-4o");
+    state.messages.push(Message::new("user", "Hi there"));
+    let reply = client_for(&server, "gpt-4o").send(&state.messages).unwrap();
+    assert_eq!(reply.role, "assistant");
+    assert_eq!(reply.text(), "Hello!");
+    state.messages.push(reply);
+
+    let path = std::env::temp_dir().join(format!("ask_rs-client-test-{}", std::process::id()));
+    state.save(&path).unwrap();
+    let loaded = ConversationState::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.model, "gpt-4o");
+    assert_eq!(loaded.messages.len(), 3);
+    assert_eq!(loaded.messages[2].text(), "Hello!");
+}
+
+#[test]
+fn missing_choices_is_an_error() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(401)
+            .body(r#"{"error":{"message":"Incorrect API key provided"}}"#);
+    });
+
+    let state = ConversationState::new("gpt-4o");
+    let err = client_for(&server, "gpt-4o")
+        .send(&state.messages)
+        .unwrap_err();
+
+    assert!(matches!(err, ask_rs::Error::UnexpectedResponse(_)));
+}
+
+#[test]
+fn tool_calls_are_kept_on_the_reply() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(
+            r#"{"choices":[{"finish_reason":"tool_calls","message":{"role":"assistant","content":null,
+               "tool_calls":[{"id":"call_1","type":"function","function":{"name":"ls","arguments":"{}"}}]}}]}"#,
+        );
+    });
+
+    let state = ConversationState::new("gpt-4o");
+    let reply = client_for(&server, "gpt-4o").send(&state.messages).unwrap();
+
+    assert!(reply.content.is_null());
+    assert_eq!(reply.tool_calls.unwrap()[0]["function"]["name"], "ls");
+}
+
+#[test]
+fn multimodal_turn_survives_reload_and_is_resent_structured() {
+    let image_part = json!({
+        "type": "image_url",
+        "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=", "detail": "high" },
+    });
+    let mut state = ConversationState::new("gpt-4o");
+    let mut content = serde_json::Value::String("What is this?".to_string());
+    ask_rs::append_content_part(&mut content, image_part.clone());
+    state.messages.push(Message {
+        role: "user".to_string(),
+        content,
+        ..Default::default()
+    });
+    state
+        .messages
+        .push(Message::new("assistant", "A tiny PNG."));
+
+    let path = std::env::temp_dir().join(format!("ask_rs-image-test-{}", std::process::id()));
+    state.save(&path).unwrap();
+    let mut reloaded = ConversationState::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    reloaded
+        .messages
+        .push(Message::new("user", "Describe it in more detail"));
+
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body_partial(
+                json!({
+                    "messages": [
+                        { "role": "system", "content": ask_rs::SEED_PROMPT },
+                        {
+                            "role": "user",
+                            "content": [
+                                { "type": "text", "text": "What is this?" },
+                                image_part,
+                            ],
+                        },
+                        { "role": "assistant", "content": "A tiny PNG." },
+                        { "role": "user", "content": "Describe it in more detail" },
+                    ],
+                })
+                .to_string(),
+            );
+        then.status(200).body(REPLY);
+    });
+
+    client_for(&server, "gpt-4o")
+        .send(&reloaded.messages)
+        .unwrap();
+
+    mock.assert();
+}
+
+#[test]
+fn yaml_transcript_round_trips_losslessly() {
+    let mut state = ConversationState::new("gpt-4o");
+    let mut content = serde_json::Value::String("What is this?".to_string());
+    ask_rs::append_content_part(
+        &mut content,
+        json!({ "type": "file", "file": { "file_id": "f1" } }),
+    );
+    state.messages.push(Message {
+        role: "user".to_string(),
+        content,
+        pinned: true,
+        ..Default::default()
+    });
+    state.files.push("f1".to_string());
+
+    let path = std::env::temp_dir().join(format!("ask_rs-yaml-test-{}.yaml", std::process::id()));
+    state.save(&path).unwrap();
+    assert!(std::fs::read_to_string(&path)
+        .unwrap()
+        .contains("model: gpt-4o"));
+    let loaded = ConversationState::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        serde_json::to_value(&loaded).unwrap(),
+        serde_json::to_value(&state).unwrap()
+    );
+}
+
+#[test]
+fn json_mode_is_only_requested_from_supporting_models() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body_partial(r#"{"response_format":{"type":"json_object"}}"#);
+        then.status(200).body(REPLY);
+    });
+    let fallback = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(REPLY);
+    });
+
+    for model in ["gpt-4o", "o1-mini"] {
+        let mut config = ClientConfig::new("test-key", model);
+        config.base_url = server.base_url();
+        config.json_mode = true;
+        let state = ConversationState::new(model);
+        Client::new(config).send(&state.messages).unwrap();
+    }
+
+    mock.assert_hits(1);
+    fallback.assert_hits(1);
+}
+
+#[test]
+fn strip_markdown_leaves_plain_text() {
+    let reply = "## Steps\n\n1. Run **`cargo build`**\n> see [docs](https://doc.rust-lang.org)\n```sh\n    cargo test\n```";
+
+    assert_eq!(
+        ask_rs::strip_markdown(reply),
+        "Steps\n\n1. Run cargo build\nsee docs (https://doc.rust-lang.org)\n    cargo test"
+    );
+}
+
+#[test]
+fn local_message_fields_are_not_sent() {
+    let server = MockServer::start();
+    let mut state = ConversationState::new("o1-mini");
+    state.messages.push(Message {
+        role: "assistant".to_string(),
+        content: json!("Hello!"),
+        pinned: true,
+        model: Some("gpt-4o".to_string()),
+        ..Default::default()
+    });
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body(json!({
+                "model": "o1-mini",
+                "messages": [
+                    { "role": "user", "content": ask_rs::SEED_PROMPT },
+                    { "role": "assistant", "content": "Hello!" },
+                ],
+            }));
+        then.status(200).body(REPLY);
+    });
+
+    client_for(&server, "o1-mini")
+        .send(&state.messages)
+        .unwrap();
+
+    mock.assert();
+}
+
+#[test]
+fn consecutive_user_turns_are_merged_for_alternating_backends() {
+    let server = MockServer::start();
+    let mut state = ConversationState::new("o1-mini");
+    state
+        .messages
+        .push(Message::new("assistant", "COMMAND: ls"));
+    state
+        .messages
+        .push(Message::new("user", "Command output: a b"));
+    state
+        .messages
+        .push(Message::new("user", "Suggest the next command."));
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .json_body(json!({
+                "model": "o1-mini",
+                "messages": [
+                    { "role": "user", "content": ask_rs::SEED_PROMPT },
+                    { "role": "assistant", "content": "COMMAND: ls" },
+                    { "role": "user", "content": "Command output: a b\n\nSuggest the next command." },
+                ],
+            }));
+        then.status(200).body(REPLY);
+    });
+
+    let mut config = ClientConfig::new("test-key", "o1-mini");
+    config.base_url = server.base_url();
+    config.alternate_roles = true;
+    Client::new(config).send(&state.messages).unwrap();
+
+    mock.assert();
+    assert_eq!(state.messages.len(), 4);
+}
+
+#[test]
+fn usage_is_kept_on_the_reply_and_priced() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(
+            r#"{"choices":[{"message":{"role":"assistant","content":"Hello!"}}],
+               "usage":{"prompt_tokens":1000,"completion_tokens":500,"total_tokens":1500}}"#,
+        );
+    });
+
+    let state = ConversationState::new("gpt-4o");
+    let reply = client_for(&server, "gpt-4o").send(&state.messages).unwrap();
+    let usage = reply.usage.unwrap();
+
+    assert_eq!((usage.prompt_tokens, usage.completion_tokens), (1000, 500));
+    assert_eq!(usage.cost("gpt-4o-mini-2024-07-18"), Some(0.00045));
+    assert_eq!(usage.cost("my-local-model"), None);
+}
+
+#[test]
+fn unversioned_transcript_is_upgraded_on_save() {
+    let path = std::env::temp_dir().join(format!("ask_rs-v0-test-{}", std::process::id()));
+    std::fs::write(
+        &path,
+        json!({
+            "model": "gpt-4o",
+            "messages": [
+                { "role": "system", "content": ask_rs::SEED_PROMPT },
+                { "role": "user", "content": "Hi there" },
+                { "role": "assistant", "content": "Hello!" },
+            ],
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    let on_disk = |path: &std::path::Path| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+
+    // Reading alone leaves the file as it was
+    let loaded = ConversationState::load(&path).unwrap();
+    let before = on_disk(&path);
+    loaded.save(&path).unwrap();
+    let after = on_disk(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.version, ask_rs::TRANSCRIPT_VERSION);
+    assert_eq!(loaded.messages.len(), 3);
+    assert_eq!(loaded.messages[2].text(), "Hello!");
+    assert!(loaded.messages[2].model.is_none());
+    assert!(before.get("version").is_none());
+    assert_eq!(after["version"], ask_rs::TRANSCRIPT_VERSION);
+    assert_eq!(after["messages"][1]["content"], "Hi there");
+}
+
+#[test]
+fn env_file_is_parsed_like_dotenv() {
+    let data = "# project settings\nexport VIRTUAL_ENV=/srv/venv\n\nAPI_URL = \"http://localhost:8000\"\nGREETING='hello world'\nEMPTY=\n";
+
+    assert_eq!(
+        ask_rs::parse_env_file(data).unwrap(),
+        vec![
+            ("VIRTUAL_ENV".to_string(), "/srv/venv".to_string()),
+            ("API_URL".to_string(), "http://localhost:8000".to_string()),
+            ("GREETING".to_string(), "hello world".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]
+    );
+    assert!(ask_rs::parse_env_file("JUST_A_NAME").is_err());
+}
+
+#[test]
+fn threads_merge_by_timestamp_and_fall_back_to_append_order() {
+    let at = |role: &str, text: &str, timestamp: Option<u64>| Message {
+        timestamp,
+        ..Message::new(role, text)
+    };
+    let texts = |messages: Vec<Message>| {
+        messages
+            .iter()
+            .map(|m| m.text().to_string())
+            .collect::<Vec<String>>()
+    };
+
+    let a = [
+        at("user", "a1", Some(10)),
+        at("assistant", "same", Some(20)),
+        at("user", "a3", Some(40)),
+    ];
+    let b = [
+        at("user", "b1", Some(15)),
+        at("assistant", "same", Some(20)),
+        at("user", "b3", Some(30)),
+    ];
+    assert_eq!(
+        texts(ask_rs::merge_by_timestamp(&a, &b)),
+        ["a1", "b1", "same", "b3", "a3"]
+    );
+
+    let untimed = [at("user", "old", None), at("assistant", "reply", None)];
+    assert_eq!(
+        texts(ask_rs::merge_by_timestamp(&untimed, &b)),
+        ["old", "reply", "b1", "same", "b3"]
+    );
+}
+
+#[test]
+fn thinking_blocks_are_stripped() {
+    assert_eq!(
+        ask_rs::strip_thinking_tags("<think>\nThe user wants a list.\n</think>\n\nUse `ls -la`."),
+        "Use `ls -la`."
+    );
+    assert_eq!(
+        ask_rs::strip_thinking_tags("Before <thinking>hmm</thinking>after"),
+        "Before after"
+    );
+    assert_eq!(ask_rs::strip_thinking_tags("No tags here"), "No tags here");
+    assert!(ask_rs::emits_thinking_tags("deepseek-r1:14b"));
+    assert!(!ask_rs::emits_thinking_tags("gpt-4o"));
+}
+
+#[test]
+fn saved_request_is_redacted_and_replays_verbatim() {
+    let mut state = ConversationState::new("gpt-4o");
+    state.messages.push(Message::new("user", "Hi there"));
+    let path = std::env::temp_dir().join(format!("ask_rs-request-test-{}", std::process::id()));
+
+    let mut config = ClientConfig::new("sk-test-0123456789abcdef", "gpt-4o");
+    config.offline = true;
+    config.save_request = Some(path.clone());
+    assert!(matches!(
+        Client::new(config).send(&state.messages),
+        Err(ask_rs::Error::Offline)
+    ));
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(saved["url"], "https://api.openai.com/v1/chat/completions");
+    assert_eq!(saved["headers"]["Authorization"], "Bearer sk-...cdef");
+    assert_eq!(saved["body"]["messages"][1]["content"], "Hi there");
+
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .header("Authorization", "Bearer test-key")
+            .json_body(saved["body"].clone());
+        then.status(200).body(REPLY);
+    });
+    let response = client_for(&server, "gpt-4o")
+        .replay_request(&saved)
+        .unwrap();
+
+    mock.assert();
+    assert_eq!(response["choices"][0]["message"]["content"], "Hello!");
+}
+
+#[test]
+fn rate_limited_key_rotates_to_the_next_one() {
+    let server = MockServer::start();
+    let limited = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .header("Authorization", "Bearer first-key");
+        then.status(429)
+            .body(r#"{"error":{"message":"Rate limit reached"}}"#);
+    });
+    let accepted = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/chat/completions")
+            .header("Authorization", "Bearer second-key");
+        then.status(200).body(REPLY);
+    });
+
+    let mut config = ClientConfig::new("first-key", "gpt-4o");
+    config.base_url = server.base_url();
+    config.fallback_keys = vec!["second-key".to_string()];
+    let client = Client::new(config);
+    let state = ConversationState::new("gpt-4o");
+
+    assert_eq!(client.send(&state.messages).unwrap().text(), "Hello!");
+    assert_eq!(client.send(&state.messages).unwrap().text(), "Hello!");
+
+    limited.assert_hits(1);
+    accepted.assert_hits(2);
+    assert_eq!(client.active_key(), 1);
+}
+
+#[test]
+fn responses_api_sends_only_turns_after_the_stored_reply() {
+    let server = MockServer::start();
+    let mut state = ConversationState::new("gpt-4o");
+    state.messages.push(Message::new("user", "Hi there"));
+    state.messages.push(Message {
+        response_id: Some("resp_1".to_string()),
+        ..Message::new("assistant", "Hello!")
+    });
+    state.messages.push(Message::new("user", "And again"));
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/responses").json_body(json!({
+            "model": "gpt-4o",
+            "input": [{ "role": "user", "content": "And again" }],
+            "store": true,
+            "previous_response_id": "resp_1",
+            "max_output_tokens": 2048,
+            "temperature": 0.6,
+        }));
+        then.status(200).body(
+            r#"{"id":"resp_2","object":"response","output":[{"type":"message","role":"assistant",
+               "content":[{"type":"output_text","text":"Hello again!"}]}],
+               "usage":{"input_tokens":30,"output_tokens":4}}"#,
+        );
+    });
+
+    let mut config = ClientConfig::new("test-key", "gpt-4o");
+    config.base_url = server.base_url();
+    config.responses_api = true;
+    let reply = Client::new(config).send(&state.messages).unwrap();
+
+    mock.assert();
+    assert_eq!(reply.text(), "Hello again!");
+    assert_eq!(reply.response_id.as_deref(), Some("resp_2"));
+    assert_eq!(reply.usage.unwrap().completion_tokens, 4);
+}
+
+#[test]
+fn unknown_roles_are_refused_before_sending() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).body(REPLY);
+    });
+    let mut state = ConversationState::new("gpt-4o");
+    state.messages.push(Message::new("human", "Hi there"));
+
+    let err = client_for(&server, "gpt-4o")
+        .send(&state.messages)
+        .unwrap_err();
+    assert!(matches!(err, ask_rs::Error::InvalidRole(1, ref role) if role == "human"));
+    mock.assert_hits(0);
+
+    let mut config = ClientConfig::new("test-key", "gpt-4o");
+    config.base_url = server.base_url();
+    config.strict_roles = false;
+    Client::new(config).send(&state.messages).unwrap();
+    mock.assert_hits(1);
+}